@@ -0,0 +1,157 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use chrono::{DateTime, Utc};
+use hyper::header::{HeaderName, CONTENT_LENGTH};
+use hyper::{HeaderMap, Request, Response};
+
+/// A piece of an access log line.
+enum FormatToken {
+    Literal(String),
+    /// `%h`
+    RemoteHost,
+    /// `%t`
+    Time,
+    /// `%r`
+    RequestLine,
+    /// `%s`
+    Status,
+    /// `%b`, taken from the `Content-Length`
+    BytesSent,
+    /// `%{HEADER}i`
+    RequestHeader(HeaderName),
+    /// `%{HEADER}o`
+    ResponseHeader(HeaderName),
+    /// `%D`, in microseconds until the response headers were ready
+    Duration,
+    /// `%I`, taken from the `Content-Length`
+    RequestBytes,
+}
+
+/// An Apache style access log format, e.g. `%h %t "%r" %s %b`.
+pub struct AccessLogFormat {
+    tokens: Vec<FormatToken>,
+}
+
+/// What we need to remember about a request until its response is ready to log.
+pub struct Pending {
+    remote_host: IpAddr,
+    time: DateTime<Utc>,
+    start: Instant,
+    request_line: String,
+    headers: HeaderMap,
+}
+
+fn push_literal(tokens: &mut Vec<FormatToken>, s: &str) {
+    match tokens.last_mut() {
+        Some(FormatToken::Literal(literal)) => literal.push_str(s),
+        _ => tokens.push(FormatToken::Literal(s.to_string())),
+    }
+}
+
+/// The body length from the `Content-Length` header, or `-` if there is none (or it is 0).
+fn content_length(headers: &HeaderMap) -> &str {
+    match headers.get(CONTENT_LENGTH).and_then(|v| v.to_str().ok()) {
+        Some(length) if length != "0" => length,
+        _ => "-",
+    }
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &HeaderName) -> &'a str {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-")
+}
+
+impl AccessLogFormat {
+    /// Parses the format string once so that rendering a line is cheap.
+    ///
+    /// Anything that is not a token we know, including `%{...}` with an invalid header name,
+    /// is kept as literal text.
+    pub fn parse(format: &str) -> AccessLogFormat {
+        let mut tokens = Vec::new();
+        let mut rest = format;
+        while let Some(start) = rest.find('%') {
+            push_literal(&mut tokens, &rest[..start]);
+            rest = &rest[start..];
+            let (token, len) = match rest.as_bytes().get(1) {
+                Some(b'%') => (FormatToken::Literal("%".to_string()), 2),
+                Some(b'h') => (FormatToken::RemoteHost, 2),
+                Some(b't') => (FormatToken::Time, 2),
+                Some(b'r') => (FormatToken::RequestLine, 2),
+                Some(b's') => (FormatToken::Status, 2),
+                Some(b'b') => (FormatToken::BytesSent, 2),
+                Some(b'D') => (FormatToken::Duration, 2),
+                Some(b'I') => (FormatToken::RequestBytes, 2),
+                Some(b'{') => match rest.find('}') {
+                    Some(end) => {
+                        let name = HeaderName::from_bytes(&rest.as_bytes()[2..end]);
+                        match (name, rest.as_bytes().get(end + 1)) {
+                            (Ok(name), Some(b'i')) => (FormatToken::RequestHeader(name), end + 2),
+                            (Ok(name), Some(b'o')) => (FormatToken::ResponseHeader(name), end + 2),
+                            _ => (FormatToken::Literal("%".to_string()), 1),
+                        }
+                    }
+                    None => (FormatToken::Literal("%".to_string()), 1),
+                },
+                _ => (FormatToken::Literal("%".to_string()), 1),
+            };
+            match token {
+                FormatToken::Literal(literal) => push_literal(&mut tokens, literal.as_str()),
+                token => tokens.push(token),
+            }
+            rest = &rest[len..];
+        }
+        push_literal(&mut tokens, rest);
+        AccessLogFormat { tokens }
+    }
+
+    /// Captures the request details before the request is handed on.
+    pub fn begin<B>(&self, remote_host: IpAddr, req: &Request<B>) -> Pending {
+        Pending {
+            remote_host,
+            time: Utc::now(),
+            start: Instant::now(),
+            request_line: format!("{} {} {:?}", req.method(), req.uri(), req.version()),
+            headers: req.headers().clone(),
+        }
+    }
+
+    /// Renders the access log line for the response to a request.
+    pub fn render<B>(&self, pending: &Pending, response: &Response<B>) -> String {
+        let mut line = String::new();
+        for token in &self.tokens {
+            let _ = match token {
+                FormatToken::Literal(literal) => write!(line, "{}", literal),
+                FormatToken::RemoteHost => write!(line, "{}", pending.remote_host),
+                FormatToken::Time => {
+                    write!(line, "{}", pending.time.format("[%d/%b/%Y:%H:%M:%S %z]"))
+                }
+                FormatToken::RequestLine => write!(line, "{}", pending.request_line),
+                FormatToken::Status => write!(line, "{}", response.status().as_u16()),
+                FormatToken::BytesSent => write!(line, "{}", content_length(response.headers())),
+                FormatToken::RequestHeader(name) => {
+                    write!(line, "{}", header(&pending.headers, name))
+                }
+                FormatToken::ResponseHeader(name) => {
+                    write!(line, "{}", header(response.headers(), name))
+                }
+                FormatToken::Duration => {
+                    write!(line, "{}", pending.start.elapsed().as_micros())
+                }
+                FormatToken::RequestBytes => write!(line, "{}", content_length(&pending.headers)),
+            };
+        }
+        line
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use futures::{Future, Stream};
+use hdrhistogram::Histogram;
+use tokio_timer::Interval;
+
+/// An upstream response header timeout that follows the P95 latency of the upstream.
+///
+/// Latencies are collected over a window, at the end of which the timeout becomes the P95
+/// times the multiplier, clamped between the minimum and maximum. It starts at the maximum.
+pub struct AdaptiveTimeout {
+    latencies: Mutex<Histogram<u64>>,
+    current_ms: AtomicU64,
+    multiplier: f64,
+    min_ms: u64,
+    max_ms: u64,
+}
+
+impl AdaptiveTimeout {
+    pub fn new(multiplier: f64, min: Duration, max: Duration) -> AdaptiveTimeout {
+        let max_ms = max.as_millis() as u64;
+        AdaptiveTimeout {
+            latencies: Mutex::new(Histogram::new_with_max(max_ms.max(2), 2).unwrap()),
+            current_ms: AtomicU64::new(max_ms),
+            multiplier,
+            min_ms: min.as_millis() as u64,
+            max_ms,
+        }
+    }
+
+    /// The timeout to use for the next request.
+    pub fn current(&self) -> Duration {
+        Duration::from_millis(self.current_ms.load(Ordering::Relaxed))
+    }
+
+    /// Records how long the upstream took to send its response headers.
+    pub fn record(&self, latency: Duration) {
+        let ms = (latency.as_millis() as u64).min(self.max_ms);
+        self.latencies.lock().unwrap().saturating_record(ms);
+    }
+
+    fn adjust(&self) {
+        let mut latencies = self.latencies.lock().unwrap();
+        if latencies.is_empty() {
+            log!(
+                "[{}] INFO Upstream timeout stays at {}ms (no responses)",
+                Utc::now(),
+                self.current_ms.load(Ordering::Relaxed)
+            );
+            return;
+        }
+        let p95 = latencies.value_at_quantile(0.95);
+        let timeout = ((p95 as f64 * self.multiplier) as u64).clamp(self.min_ms, self.max_ms);
+        self.current_ms.store(timeout, Ordering::Relaxed);
+        log!(
+            "[{}] INFO Upstream timeout is now {}ms (P95 {}ms over {} responses)",
+            Utc::now(),
+            timeout,
+            p95,
+            latencies.len()
+        );
+        latencies.reset();
+    }
+}
+
+/// Recomputes the timeout from the latencies seen at the end of every `window`.
+///
+/// A window without any responses leaves the timeout as it was.
+pub fn adjust_every(
+    timeout: Arc<AdaptiveTimeout>,
+    window: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now() + window, window)
+        .map_err(|e| log_error!("adaptive timeout timer error: {}", e))
+        .for_each(move |_| {
+            timeout.adjust();
+            Ok(())
+        })
+}
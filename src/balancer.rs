@@ -0,0 +1,265 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::{Poll, Stream};
+use hyper::body::Payload;
+use hyper::header::HeaderValue;
+use hyper::{Body, Chunk, Response, Uri};
+use rand::seq::index;
+use rand::Rng;
+
+use crate::grpc;
+
+/// How to pick the backend for each request.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    RoundRobin,
+    LeastConnections,
+    Random,
+    /// The same client IP always goes to the same backend, while the backends stay the same.
+    IpHash,
+    /// The less loaded of two backends picked at random.
+    PowerOfTwoChoices,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Strategy, String> {
+        match s {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            "random" => Ok(Strategy::Random),
+            "ip-hash" => Ok(Strategy::IpHash),
+            "power-of-two-choices" => Ok(Strategy::PowerOfTwoChoices),
+            _ => Err(format!(
+                "Unknown balancer strategy '{}', expected one of: round-robin, least-connections, random, ip-hash, power-of-two-choices",
+                s
+            )),
+        }
+    }
+}
+
+/// One of the target base URLs that requests can be forwarded to.
+pub struct Backend {
+    pub url: String,
+    /// The `Host` header to send when rewriting the host to the backend's.
+    pub host: HeaderValue,
+    in_flight: Arc<AtomicI64>,
+}
+
+impl Backend {
+    pub fn parse(url: &str) -> Result<Backend, String> {
+        let url = url.trim_end_matches('/');
+        let uri = Uri::from_str(url).map_err(|_| format!("The target URL {} is not valid", url))?;
+        let host = match uri.authority_part() {
+            Some(authority) => HeaderValue::from_str(authority.as_str()).unwrap(),
+            None => return Err(format!("The target URL {} must include a host", url)),
+        };
+        Ok(Backend {
+            url: url.to_string(),
+            host,
+            in_flight: Arc::new(AtomicI64::new(0)),
+        })
+    }
+
+    /// Counts a request against this backend until the returned guard is dropped.
+    pub fn start(&self) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self.in_flight.clone())
+    }
+
+    fn in_flight(&self) -> i64 {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+}
+
+/// Tracks an in-flight request to a backend.
+pub struct InFlight(Arc<AtomicI64>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Keeps a request counted against its backend until the body of its response has been
+/// sent (or dropped), as a backend is still busy while it streams a response.
+///
+/// gRPC bodies are left alone as the wrapping would drop their trailers, and bodies that are
+/// already finished, like those of upgrades, have nothing left to wait for, so for those the
+/// request stops counting with the headers.
+pub fn hold(response: Response<Body>, in_flight: InFlight) -> Response<Body> {
+    if response.body().is_end_stream() || grpc::is_grpc(response.headers()) {
+        return response;
+    }
+    let (parts, body) = response.into_parts();
+    let body = InFlightBody {
+        inner: body,
+        _in_flight: in_flight,
+    };
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// A response body that holds on to the [`InFlight`] of its request.
+struct InFlightBody {
+    inner: Body,
+    _in_flight: InFlight,
+}
+
+impl Stream for InFlightBody {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        self.inner.poll()
+    }
+}
+
+pub struct Balancer {
+    backends: Vec<Backend>,
+    strategy: Strategy,
+    next: AtomicUsize,
+}
+
+impl Balancer {
+    pub fn new(backends: Vec<Backend>, strategy: Strategy) -> Balancer {
+        Balancer {
+            backends,
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Picks the backend for the next request from `client`, or `None` if there are no backends.
+    pub fn select(&self, client: IpAddr) -> Option<&Backend> {
+        self.select_untried(client, &[])
+    }
+
+    /// Picks a backend for `client` in the same way as [`select`](Balancer::select), but only
+    /// from those whose URL is not in `tried`, or `None` if there are none left.
+    ///
+    /// With [`Strategy::IpHash`] this is the next untried backend after the client's own, so
+    /// that a client's retries also keep going to the same place.
+    pub fn select_untried(&self, client: IpAddr, tried: &[String]) -> Option<&Backend> {
+        let candidates: Vec<usize> = (0..self.backends.len())
+            .filter(|&i| !tried.contains(&self.backends[i].url))
+            .collect();
+        if candidates.len() <= 1 {
+            return candidates.first().map(|&i| &self.backends[i]);
+        }
+        let count = candidates.len();
+        let index = match self.strategy {
+            Strategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                candidates[next % count]
+            }
+            Strategy::LeastConnections => {
+                // start from a rotating offset so ties are shared out evenly
+                let offset = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..count)
+                    .map(|i| candidates[(offset + i) % count])
+                    .min_by_key(|&i| self.backends[i].in_flight())
+                    .unwrap()
+            }
+            Strategy::Random => candidates[rand::thread_rng().gen_range(0, count)],
+            Strategy::IpHash => {
+                let mut hasher = DefaultHasher::new();
+                client.hash(&mut hasher);
+                let hashed = (hasher.finish() % self.backends.len() as u64) as usize;
+                candidates
+                    .iter()
+                    .copied()
+                    .find(|&i| i >= hashed)
+                    .unwrap_or(candidates[0])
+            }
+            Strategy::PowerOfTwoChoices => {
+                let picked = index::sample(&mut rand::thread_rng(), count, 2);
+                let (a, b) = (candidates[picked.index(0)], candidates[picked.index(1)]);
+                if self.backends[b].in_flight() < self.backends[a].in_flight() {
+                    b
+                } else {
+                    a
+                }
+            }
+        };
+        Some(&self.backends[index])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn balancer(strategy: Strategy) -> Balancer {
+        let backends = ["http://a", "http://b", "http://c"]
+            .iter()
+            .map(|url| Backend::parse(url).unwrap())
+            .collect();
+        Balancer::new(backends, strategy)
+    }
+
+    const STRATEGIES: [Strategy; 5] = [
+        Strategy::RoundRobin,
+        Strategy::LeastConnections,
+        Strategy::Random,
+        Strategy::IpHash,
+        Strategy::PowerOfTwoChoices,
+    ];
+
+    #[test]
+    fn select_untried_skips_the_backends_already_tried() {
+        let client = IpAddr::from([10, 0, 0, 1]);
+        let tried = vec!["http://a".to_string(), "http://c".to_string()];
+        for strategy in STRATEGIES.iter() {
+            let balancer = balancer(*strategy);
+            for _ in 0..10 {
+                let backend = balancer.select_untried(client, &tried[..1]).unwrap();
+                assert_ne!(backend.url, "http://a", "{:?}", strategy);
+                let backend = balancer.select_untried(client, &tried).unwrap();
+                assert_eq!(backend.url, "http://b", "{:?}", strategy);
+            }
+        }
+    }
+
+    #[test]
+    fn select_untried_is_none_once_every_backend_is_tried() {
+        let client = IpAddr::from([10, 0, 0, 1]);
+        let tried: Vec<_> = ["http://a", "http://b", "http://c"]
+            .iter()
+            .map(|url| url.to_string())
+            .collect();
+        for strategy in STRATEGIES.iter() {
+            assert!(balancer(*strategy).select_untried(client, &tried).is_none());
+        }
+    }
+
+    #[test]
+    fn ip_hash_moves_on_to_the_next_backend() {
+        let balancer = balancer(Strategy::IpHash);
+        let client = IpAddr::from([10, 0, 0, 1]);
+        let first = balancer.select(client).unwrap().url.clone();
+        let position = balancer
+            .backends
+            .iter()
+            .position(|b| b.url == first)
+            .unwrap();
+        let next = &balancer.backends[(position + 1) % 3].url;
+        let tried = [first];
+        for _ in 0..10 {
+            assert_eq!(&balancer.select_untried(client, &tried).unwrap().url, next);
+        }
+    }
+}
@@ -0,0 +1,209 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use futures::future::{self, Future};
+use futures::{Async, Poll, Stream};
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::http::request::Parts;
+use hyper::{Body, Chunk, Request, Response, StatusCode};
+
+use crate::errors::{ErrorFormat, ErrorStyle};
+use crate::logging::RequestTag;
+use crate::BoxFut;
+
+/// The error returned by a [`BoundedBody`] once its limit has been exceeded.
+#[derive(Debug)]
+pub struct BodyTooLarge {
+    limit: u64,
+}
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "body exceeds the limit of {} bytes", self.limit)
+    }
+}
+
+impl Error for BodyTooLarge {}
+
+/// Wraps a body, counting bytes as they arrive and failing once more than `limit` bytes are read.
+///
+/// This does not rely on `Content-Length` so it also covers chunked transfer encoding.
+pub struct BoundedBody<S> {
+    inner: S,
+    limit: u64,
+    read: u64,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl<S> BoundedBody<S> {
+    pub fn new(inner: S, limit: u64) -> BoundedBody<S> {
+        BoundedBody {
+            inner,
+            limit,
+            read: 0,
+            exceeded: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// A flag that is set when the limit has been exceeded, so that the error can be
+    /// reported to the client after the body has been handed off.
+    pub fn exceeded(&self) -> Arc<AtomicBool> {
+        self.exceeded.clone()
+    }
+}
+
+impl BoundedBody<Body> {
+    pub fn into_body(self) -> Body {
+        Body::wrap_stream(self)
+    }
+}
+
+impl<S> Stream for BoundedBody<S>
+where
+    S: Stream<Item = Chunk>,
+    S::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Item = Chunk;
+    type Error = Box<dyn Error + Send + Sync>;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, Self::Error> {
+        match self.inner.poll().map_err(Into::into)? {
+            Async::Ready(Some(chunk)) => {
+                self.read += chunk.len() as u64;
+                if self.read > self.limit {
+                    self.exceeded.store(true, Ordering::SeqCst);
+                    return Err(Box::new(BodyTooLarge { limit: self.limit }));
+                }
+                Ok(Async::Ready(Some(chunk)))
+            }
+            other => Ok(other),
+        }
+    }
+}
+
+/// Buffers the body of a request, resolving to `None` if it is more than `limit` bytes.
+pub fn buffer_request(
+    req: Request<Body>,
+    limit: u64,
+) -> impl Future<Item = Option<(Parts, Chunk)>, Error = hyper::Error> {
+    let (parts, body) = req.into_parts();
+    let bounded = BoundedBody::new(body, limit);
+    let exceeded = bounded.exceeded();
+    bounded.into_body().concat2().then(move |body| match body {
+        Ok(body) => Ok(Some((parts, body))),
+        Err(_) if exceeded.load(Ordering::SeqCst) => Ok(None),
+        Err(e) => Err(e),
+    })
+}
+
+/// Collects what is written to it, failing with a [`BodyTooLarge`] once more than `limit`
+/// bytes have been written, e.g. by a decompressor.
+pub struct BoundedWriter {
+    written: Vec<u8>,
+    limit: u64,
+}
+
+impl BoundedWriter {
+    pub fn new(limit: u64) -> BoundedWriter {
+        BoundedWriter {
+            written: Vec::new(),
+            limit,
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.written
+    }
+}
+
+impl Write for BoundedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if (self.written.len() + buf.len()) as u64 > self.limit {
+            return Err(io::Error::other(BodyTooLarge { limit: self.limit }));
+        }
+        self.written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether an I/O error came from a [`BoundedWriter`] going over its limit.
+pub fn is_too_large(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|e| e.is::<BodyTooLarge>())
+}
+
+pub fn upstream_response_too_large(style: &ErrorStyle) -> Response<Body> {
+    let mut response = style.response(
+        StatusCode::BAD_GATEWAY,
+        "the upstream response is too large",
+        ErrorFormat::Text,
+    );
+    response.headers_mut().insert(
+        "X-Proxy-Error",
+        HeaderValue::from_static("upstream-response-too-large"),
+    );
+    response
+}
+
+/// Replaces an upstream response with a 502 if its body is larger than `limit` bytes.
+///
+/// Responses with a `Content-Length` are checked up front and otherwise streamed. Without
+/// one the body has to be buffered (up to the limit) so that we can still change the status.
+/// Upgraded connections and event streams are long lived by design and are never limited.
+pub fn limit_response(
+    response: Response<Body>,
+    limit: u64,
+    tag: RequestTag,
+    style: ErrorStyle,
+) -> BoxFut {
+    let is_event_stream = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    if response.status() == StatusCode::SWITCHING_PROTOCOLS || is_event_stream {
+        return Box::new(future::ok(response));
+    }
+    let content_length = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match content_length {
+        Some(length) if length > limit => {
+            log_error!(
+                "{}Error: upstream response of {} bytes is too large",
+                tag,
+                length
+            );
+            Box::new(future::ok(upstream_response_too_large(&style)))
+        }
+        Some(_) => Box::new(future::ok(response)),
+        None => {
+            let (parts, body) = response.into_parts();
+            Box::new(BoundedBody::new(body, limit).concat2().then(move |body| {
+                Ok(match body {
+                    Ok(body) => Response::from_parts(parts, Body::from(body)),
+                    Err(e) => {
+                        log_error!("{}Error: upstream response {}", tag, e);
+                        upstream_response_too_large(&style)
+                    }
+                })
+            }))
+        }
+    }
+}
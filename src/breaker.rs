@@ -0,0 +1,157 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use futures::{Future, Stream};
+use hyper::StatusCode;
+use tokio_timer::Interval;
+
+/// Below this many upstream responses in a window the error rate is not trusted.
+const MIN_RESPONSES_PER_WINDOW: usize = 10;
+
+/// Below this share of requests a decaying breaker stops turning any away.
+const MIN_SHED: f64 = 0.01;
+
+/// Sheds a growing share of requests as the upstream error rate climbs.
+///
+/// The error rate of each window decides how many requests are turned away in the next:
+/// none below the warning threshold, all at or above the open threshold, and a linearly
+/// growing share in between. A window with too few responses to judge halves the share,
+/// which is how a fully open breaker lets requests through again to find out whether the
+/// upstream has recovered.
+pub struct ErrorRateBreaker {
+    warn: f64,
+    open: f64,
+    responses: AtomicUsize,
+    errors: AtomicUsize,
+    /// The share of requests to turn away, as `f64` bits.
+    shed: AtomicU64,
+}
+
+impl ErrorRateBreaker {
+    /// Thresholds are percentages, with `warn` below `open`.
+    pub fn new(warn: f64, open: f64) -> ErrorRateBreaker {
+        ErrorRateBreaker {
+            warn: warn / 100.0,
+            open: open / 100.0,
+            responses: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+            shed: AtomicU64::new(0f64.to_bits()),
+        }
+    }
+
+    fn shed(&self) -> f64 {
+        f64::from_bits(self.shed.load(Ordering::Relaxed))
+    }
+
+    /// Whether to send the next request upstream, rather than turning it away.
+    pub fn admit(&self) -> bool {
+        let shed = self.shed();
+        shed <= 0.0 || rand::random::<f64>() >= shed
+    }
+
+    /// Counts an upstream response, where a 5xx is an error.
+    pub fn record(&self, status: StatusCode) {
+        self.responses.fetch_add(1, Ordering::Relaxed);
+        if status.is_server_error() {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn update(&self) {
+        let responses = self.responses.swap(0, Ordering::Relaxed);
+        let errors = self.errors.swap(0, Ordering::Relaxed);
+        let previous = self.shed();
+        let shed = if responses < MIN_RESPONSES_PER_WINDOW {
+            let decayed = previous / 2.0;
+            if decayed < MIN_SHED {
+                0.0
+            } else {
+                decayed
+            }
+        } else {
+            let rate = errors as f64 / responses as f64;
+            ((rate - self.warn) / (self.open - self.warn)).clamp(0.0, 1.0)
+        };
+        self.shed.store(shed.to_bits(), Ordering::Relaxed);
+        if shed > 0.0 {
+            log_error!(
+                "[{}] WARN Upstream errors at {}/{} responses, turning away {:.0}% of requests",
+                Utc::now(),
+                errors,
+                responses,
+                shed * 100.0
+            );
+        } else if previous > 0.0 {
+            log!(
+                "[{}] INFO Upstream errors at {}/{} responses, no longer turning away requests",
+                Utc::now(),
+                errors,
+                responses
+            );
+        }
+    }
+}
+
+/// Works out the share of requests to turn away at the end of every `window`.
+pub fn update_every(
+    breaker: Arc<ErrorRateBreaker>,
+    window: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now() + window, window)
+        .map_err(|e| log_error!("error rate breaker timer error: {}", e))
+        .for_each(move |_| {
+            breaker.update();
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(breaker: &ErrorRateBreaker, responses: usize, errors: usize) {
+        for i in 0..responses {
+            breaker.record(if i < errors {
+                StatusCode::BAD_GATEWAY
+            } else {
+                StatusCode::OK
+            });
+        }
+        breaker.update();
+    }
+
+    #[test]
+    fn sheds_in_proportion_between_the_thresholds() {
+        let breaker = ErrorRateBreaker::new(10.0, 50.0);
+        window(&breaker, 100, 5);
+        assert_eq!(breaker.shed(), 0.0);
+        window(&breaker, 100, 30);
+        assert!((breaker.shed() - 0.5).abs() < 1e-9);
+        window(&breaker, 100, 60);
+        assert_eq!(breaker.shed(), 1.0);
+    }
+
+    #[test]
+    fn decays_through_windows_with_too_few_responses() {
+        let breaker = ErrorRateBreaker::new(10.0, 50.0);
+        window(&breaker, 100, 100);
+        window(&breaker, 0, 0);
+        assert_eq!(breaker.shed(), 0.5);
+        window(&breaker, 3, 3);
+        assert_eq!(breaker.shed(), 0.25);
+        for _ in 0..5 {
+            window(&breaker, 0, 0);
+        }
+        assert_eq!(breaker.shed(), 0.0);
+    }
+}
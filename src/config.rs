@@ -0,0 +1,316 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::Utc;
+use futures::{Future, Stream};
+use getopts::{Matches, Options};
+use hyper::header::{HeaderValue, CACHE_CONTROL};
+use tokio_signal::unix::{Signal, SIGHUP};
+
+use crate::balancer::Backend;
+use crate::glob::Glob;
+use crate::headers::{self, ResponseHeaders};
+use crate::vhost::HostRules;
+
+/// The options in the contents of a configuration file, as command line arguments for each
+/// line.
+///
+/// Each line is a long option name, optionally followed by its value after whitespace or
+/// `=`, e.g. `add-response-header = X-Frame-Options:DENY`. Blank lines and lines starting
+/// with `#` are ignored.
+fn file_args(contents: &str) -> Vec<Vec<String>> {
+    let mut lines = Vec::new();
+    for line in contents.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let split = line
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(line.len());
+        let (name, value) = line.split_at(split);
+        let mut args = vec![format!("--{}", name.trim_start_matches('-'))];
+        let value = value.trim_start().trim_start_matches('=').trim();
+        if !value.is_empty() {
+            args.push(value.to_string());
+        }
+        lines.push(args);
+    }
+    lines
+}
+
+/// Parses the command line `args` together with the options in the contents of a
+/// configuration file.
+///
+/// An option given on the command line replaces all of its values from the file, so the
+/// file can hold the defaults that a command line overrides.
+fn parse_with(opts: &Options, args: &[String], contents: &str) -> Result<Matches, String> {
+    let command_line = opts.parse(args).map_err(|e| e.to_string())?;
+    let mut all_args = args.to_vec();
+    for line in file_args(contents) {
+        let name = line[0].trim_start_matches('-');
+        // a line that does not parse on its own is kept so that the error is reported
+        let overridden = opts.parse(&line).is_ok() && command_line.opt_present(name);
+        if !overridden {
+            all_args.extend(line);
+        }
+    }
+    opts.parse(&all_args).map_err(|e| e.to_string())
+}
+
+/// Parses the command line `args` together with the options in the configuration file at
+/// `path`, with those on the command line taking precedence.
+pub fn parse(opts: &Options, args: &[String], path: &str) -> Result<Matches, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    parse_with(opts, args, contents.as_str())
+}
+
+/// An upstream response header timeout for the requests whose path matches a glob.
+pub struct PathTimeout {
+    glob: Glob,
+    timeout: Duration,
+}
+
+impl PathTimeout {
+    /// Parses a `GLOB=MS` specification from the command line.
+    pub fn parse(spec: &str) -> Result<PathTimeout, String> {
+        let (glob, millis) = match spec.rfind('=') {
+            Some(split) => (spec[..split].trim(), spec[split + 1..].trim()),
+            None => return Err(format!("Expected GLOB=MS but got '{}'", spec)),
+        };
+        match millis.parse::<u64>() {
+            Ok(millis) if millis > 0 && !glob.is_empty() => Ok(PathTimeout {
+                glob: Glob::new(glob),
+                timeout: Duration::from_millis(millis),
+            }),
+            _ => Err(format!(
+                "Expected GLOB=MS with a positive timeout but got '{}'",
+                spec
+            )),
+        }
+    }
+}
+
+/// The timeout that `--upstream-timeout-path` picked for a request, carried in its extensions
+/// so that retried attempts use it too.
+#[derive(Clone, Copy)]
+pub struct RouteTimeout(pub Duration);
+
+/// The upstream response header timeout of a request: that of its path if one matched,
+/// otherwise the `global` one.
+pub fn effective_timeout(
+    route: Option<&RouteTimeout>,
+    global: Option<Duration>,
+) -> Option<Duration> {
+    route.map(|route| route.0).or(global)
+}
+
+/// The settings that can be changed by reloading the configuration file.
+pub struct Config {
+    pub response_headers: ResponseHeaders,
+    pub host_rules: HostRules<Backend>,
+    pub strip_query_string: bool,
+    pub strip_query_string_paths: Vec<Glob>,
+    pub timeout_paths: Vec<PathTimeout>,
+}
+
+impl Config {
+    pub fn from_matches(matches: &Matches) -> Result<Config, String> {
+        let mut response_headers = ResponseHeaders::default();
+        if let Some(v) = matches.opt_str("c") {
+            match HeaderValue::from_str(v.as_str()) {
+                Ok(value) => response_headers.set_if_missing(CACHE_CONTROL, value),
+                Err(_) => {
+                    return Err(
+                        "The cache control header value is not a valid header value".to_string()
+                    )
+                }
+            }
+        }
+        for spec in matches.opt_strs("add-response-header-if-missing") {
+            let (name, value) = headers::parse_header(spec.as_str())?;
+            response_headers.set_if_missing(name, value);
+        }
+        for spec in matches.opt_strs("add-response-header") {
+            let (name, value) = headers::parse_header(spec.as_str())?;
+            response_headers.set(name, value);
+        }
+        let mut host_rules = HostRules::default();
+        for spec in matches.opt_strs("sni-rewrite") {
+            host_rules.add(spec.as_str(), Backend::parse)?;
+        }
+        let mut timeout_paths = Vec::new();
+        for spec in matches.opt_strs("upstream-timeout-path") {
+            timeout_paths.push(PathTimeout::parse(spec.as_str())?);
+        }
+        Ok(Config {
+            timeout_paths,
+            response_headers,
+            host_rules,
+            strip_query_string: matches.opt_present("strip-query-string"),
+            strip_query_string_paths: matches
+                .opt_strs("strip-query-string-path")
+                .iter()
+                .map(|glob| Glob::new(glob))
+                .collect(),
+        })
+    }
+
+    /// The timeout of the first `--upstream-timeout-path` whose glob matches `path`.
+    pub fn route_timeout(&self, path: &str) -> Option<RouteTimeout> {
+        self.timeout_paths
+            .iter()
+            .find(|route| route.glob.matches(path))
+            .map(|route| RouteTimeout(route.timeout))
+    }
+}
+
+fn reload(opts: &Options, args: &[String], path: &str) -> Result<Config, String> {
+    Config::from_matches(&parse(opts, args, path)?)
+}
+
+/// Reloads the configuration file whenever we get a `SIGHUP`, keeping the command line `args`.
+///
+/// Only the settings in [`Config`] are updated, and a configuration that fails to load
+/// leaves the current one in place.
+pub fn reload_on_sighup(
+    current: Arc<RwLock<Arc<Config>>>,
+    opts: Options,
+    args: Vec<String>,
+    path: String,
+) -> impl Future<Item = (), Error = ()> {
+    Signal::new(SIGHUP)
+        .flatten_stream()
+        .map_err(|e| log_error!("SIGHUP handler error: {}", e))
+        .for_each(move |_| {
+            match reload(&opts, &args, path.as_str()) {
+                Ok(config) => {
+                    *current.write().unwrap() = Arc::new(config);
+                    log!("[{}] Reloaded configuration from {}", Utc::now(), path);
+                }
+                Err(e) => log_error!(
+                    "[{}] Could not reload configuration from {}: {}",
+                    Utc::now(),
+                    path,
+                    e
+                ),
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> Options {
+        let mut opts = Options::new();
+        opts.optopt("p", "port", "", "PORT");
+        opts.optflag("", "strip-query-string", "");
+        opts.optmulti("", "add-response-header", "", "NAME:VALUE");
+        opts
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn file_args_accepts_whitespace_or_equals() {
+        let lines = file_args(
+            "# comment\n\nport = 9090\nstrip-query-string\n--add-response-header X-A:1\n",
+        );
+        assert_eq!(
+            lines,
+            vec![
+                args(&["--port", "9090"]),
+                args(&["--strip-query-string"]),
+                args(&["--add-response-header", "X-A:1"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn file_provides_options_missing_from_the_command_line() {
+        let matches =
+            parse_with(&options(), &args(&["-p", "8099"]), "strip-query-string\n").unwrap();
+        assert_eq!(matches.opt_str("port").as_deref(), Some("8099"));
+        assert!(matches.opt_present("strip-query-string"));
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let contents = "port = 9090\nstrip-query-string\nadd-response-header = X-A:1\n";
+        let matches = parse_with(
+            &options(),
+            &args(&[
+                "-p",
+                "8099",
+                "--strip-query-string",
+                "--add-response-header",
+                "X-B:2",
+            ]),
+            contents,
+        )
+        .unwrap();
+        assert_eq!(matches.opt_str("port").as_deref(), Some("8099"));
+        assert_eq!(matches.opt_strs("add-response-header"), args(&["X-B:2"]));
+    }
+
+    #[test]
+    fn parses_path_timeouts() {
+        let route = PathTimeout::parse("/reports/**=120000").unwrap();
+        assert!(route.glob.matches("/reports/2019/q4"));
+        assert_eq!(route.timeout, Duration::from_secs(120));
+        assert!(PathTimeout::parse("/a=b=500").unwrap().glob.matches("/a=b"));
+    }
+
+    #[test]
+    fn rejects_invalid_path_timeouts() {
+        assert!(PathTimeout::parse("/reports/**").is_err());
+        assert!(PathTimeout::parse("/reports/**=soon").is_err());
+        assert!(PathTimeout::parse("/reports/**=0").is_err());
+        assert!(PathTimeout::parse("=500").is_err());
+    }
+
+    #[test]
+    fn first_matching_path_picks_the_timeout() {
+        let config = |contents| {
+            let matches = parse_with(&crate::create_options(), &[], contents).unwrap();
+            Config::from_matches(&matches).unwrap()
+        };
+        let routes =
+            config("upstream-timeout-path = /health=500\nupstream-timeout-path = /**=120000\n");
+        let timeout = |path| routes.route_timeout(path).map(|route| route.0);
+        assert_eq!(timeout("/health"), Some(Duration::from_millis(500)));
+        assert_eq!(timeout("/reports/q4"), Some(Duration::from_secs(120)));
+        assert!(config("").route_timeout("/health").is_none());
+    }
+
+    #[test]
+    fn route_timeout_takes_precedence_over_the_global_one() {
+        let global = Some(Duration::from_secs(30));
+        let route = RouteTimeout(Duration::from_millis(500));
+        assert_eq!(
+            effective_timeout(Some(&route), global),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(effective_timeout(Some(&route), None), Some(route.0));
+        assert_eq!(effective_timeout(None, global), global);
+        assert_eq!(effective_timeout(None, None), None);
+    }
+
+    #[test]
+    fn unknown_file_options_are_errors() {
+        assert!(parse_with(&options(), &[], "no-such-option = 1\n").is_err());
+    }
+}
@@ -0,0 +1,434 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use futures::future::{self, Future};
+use futures::Poll;
+use hyper::client::connect::{Connect, Connected, Destination, HttpConnector};
+use rustls::ClientConfig;
+use socket2::SockRef;
+use tokio_io::io::write_all;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use tokio_tcp::TcpStream;
+use webpki::DNSNameRef;
+
+use crate::dns::CachingResolver;
+
+/// A connection to the upstream which may or may not be using TLS.
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl Read for MaybeTlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MaybeTlsStream::Plain(s) => s.read(buf),
+            MaybeTlsStream::Tls(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for MaybeTlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MaybeTlsStream::Plain(s) => s.write(buf),
+            MaybeTlsStream::Tls(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MaybeTlsStream::Plain(s) => s.flush(),
+            MaybeTlsStream::Tls(s) => s.flush(),
+        }
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            MaybeTlsStream::Plain(s) => AsyncWrite::shutdown(s),
+            MaybeTlsStream::Tls(s) => AsyncWrite::shutdown(s),
+        }
+    }
+}
+
+/// The version of the PROXY protocol header to start upstream connections with.
+#[derive(Clone, Copy)]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ProxyProtocol, String> {
+        match s {
+            "v1" | "1" => Ok(ProxyProtocol::V1),
+            "v2" | "2" => Ok(ProxyProtocol::V2),
+            _ => Err(format!(
+                "PROXY protocol version is supposed to be v1 or v2 but got {}",
+                s
+            )),
+        }
+    }
+}
+
+/// Both addresses in the same family, mapping IPv4 into IPv6 if only one of them is IPv6.
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let to_v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (to_v6(source), to_v6(destination))
+    }
+}
+
+impl ProxyProtocol {
+    /// The header announcing a connection from `source` to `destination`.
+    fn header(self, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+        let (source, destination) = same_family(source, destination);
+        match self {
+            ProxyProtocol::V1 => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            ProxyProtocol::V2 => {
+                let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+                // version 2, PROXY command
+                header.push(0x21);
+                let mut addresses = Vec::new();
+                match (source.ip(), destination.ip()) {
+                    (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                        header.push(0x11); // TCP over IPv4
+                        addresses.extend_from_slice(&src.octets());
+                        addresses.extend_from_slice(&dst.octets());
+                    }
+                    (src, dst) => {
+                        header.push(0x21); // TCP over IPv6
+                        for ip in &[src, dst] {
+                            if let IpAddr::V6(ip) = ip {
+                                addresses.extend_from_slice(&ip.octets());
+                            }
+                        }
+                    }
+                }
+                addresses.extend_from_slice(&source.port().to_be_bytes());
+                addresses.extend_from_slice(&destination.port().to_be_bytes());
+                header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+                header.extend_from_slice(&addresses);
+                header
+            }
+        }
+    }
+}
+
+/// TCP keep-alive probe settings for upstream connections, where unset ones keep the OS default.
+#[derive(Clone, Copy, Default)]
+pub struct TcpKeepalive {
+    /// How long a connection is idle before the first probe (`TCP_KEEPIDLE`).
+    pub idle: Option<Duration>,
+    /// How long to wait between probes (`TCP_KEEPINTVL`).
+    pub interval: Option<Duration>,
+    /// How many unanswered probes drop the connection (`TCP_KEEPCNT`).
+    pub count: Option<u32>,
+}
+
+/// Whether the probe interval and count can be set on this platform, rather than only the idle time.
+pub const PROBE_SETTINGS_SUPPORTED: bool = cfg!(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "fuchsia",
+    target_os = "illumos",
+    target_os = "linux",
+    target_os = "netbsd",
+    target_vendor = "apple",
+));
+
+impl TcpKeepalive {
+    fn apply(&self, tcp: &TcpStream) -> io::Result<()> {
+        let mut keepalive = socket2::TcpKeepalive::new();
+        if let Some(idle) = self.idle {
+            keepalive = keepalive.with_time(idle);
+        }
+        #[cfg(any(
+            target_os = "android",
+            target_os = "dragonfly",
+            target_os = "freebsd",
+            target_os = "fuchsia",
+            target_os = "illumos",
+            target_os = "linux",
+            target_os = "netbsd",
+            target_vendor = "apple",
+        ))]
+        {
+            if let Some(interval) = self.interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            if let Some(count) = self.count {
+                keepalive = keepalive.with_retries(count);
+            }
+        }
+        SockRef::from(tcp).set_tcp_keepalive(&keepalive)
+    }
+}
+
+/// How long it took to open an upstream connection, found in the extensions of every
+/// response received over it.
+#[derive(Clone, Copy)]
+pub struct ConnectTiming {
+    /// When the connection was ready.
+    pub at: Instant,
+    /// How long it took to resolve, connect and, for `https`, finish the TLS handshake.
+    pub took: Duration,
+}
+
+impl ConnectTiming {
+    fn since(started: Instant) -> ConnectTiming {
+        let at = Instant::now();
+        ConnectTiming {
+            at,
+            took: at - started,
+        }
+    }
+}
+
+/// Sets how long sent data may go unacknowledged before the connection is dropped
+/// (`TCP_USER_TIMEOUT`), which only Linux supports, so elsewhere it does nothing.
+fn set_user_timeout(tcp: &TcpStream, timeout: Option<Duration>) -> io::Result<()> {
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    {
+        if timeout.is_some() {
+            return SockRef::from(tcp).set_tcp_user_timeout(timeout);
+        }
+    }
+    #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+    let _ = (tcp, timeout);
+    Ok(())
+}
+
+/// Local addresses that outgoing connections take turns to bind to, one per network interface.
+pub struct InterfaceAddresses {
+    addresses: RwLock<Vec<(String, IpAddr)>>,
+    next: AtomicUsize,
+}
+
+impl InterfaceAddresses {
+    /// Looks up the address of each named interface, preferring IPv4 addresses.
+    pub fn lookup(names: &[String]) -> Result<InterfaceAddresses, String> {
+        let interfaces = if_addrs::get_if_addrs()
+            .map_err(|e| format!("Could not list the network interfaces: {}", e))?;
+        let mut addresses = Vec::new();
+        for name in names {
+            let address = interfaces
+                .iter()
+                .filter(|i| &i.name == name)
+                .map(|i| i.ip())
+                .min_by_key(|ip| ip.is_ipv6())
+                .ok_or_else(|| format!("Network interface {} has no address", name))?;
+            addresses.push((name.clone(), address));
+        }
+        Ok(InterfaceAddresses {
+            addresses: RwLock::new(addresses),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    fn next(&self) -> Option<(String, IpAddr)> {
+        let addresses = self.addresses.read().unwrap();
+        if addresses.is_empty() {
+            return None;
+        }
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % addresses.len();
+        Some(addresses[index].clone())
+    }
+
+    /// Stops using an interface whose address has gone away.
+    fn remove(&self, name: &str) {
+        let mut addresses = self.addresses.write().unwrap();
+        if let Some(index) = addresses.iter().position(|(n, _)| n == name) {
+            addresses.remove(index);
+            log_error!(
+                "[{}] WARN Network interface {} is no longer available, {} left",
+                Utc::now(),
+                name,
+                addresses.len()
+            );
+        }
+    }
+}
+
+/// Connects to `http` upstreams over plain TCP and `https` upstreams over TLS.
+///
+/// The TLS configuration is read on every new connection so that it can be swapped
+/// out (e.g. when a client certificate is rotated) without rebuilding the client.
+#[derive(Clone)]
+pub struct Connector {
+    http: HttpConnector<CachingResolver>,
+    tls: Arc<RwLock<Arc<ClientConfig>>>,
+    local_address: Option<IpAddr>,
+    proxy_header: Option<(ProxyProtocol, SocketAddr)>,
+    tcp_keepalive: Option<TcpKeepalive>,
+    tcp_user_timeout: Option<Duration>,
+    interfaces: Option<Arc<InterfaceAddresses>>,
+}
+
+impl Connector {
+    pub fn new(resolver: CachingResolver, tls: Arc<RwLock<Arc<ClientConfig>>>) -> Connector {
+        let mut http = HttpConnector::new_with_resolver(resolver);
+        http.enforce_http(false);
+        Connector {
+            http,
+            tls,
+            local_address: None,
+            proxy_header: None,
+            tcp_keepalive: None,
+            tcp_user_timeout: None,
+            interfaces: None,
+        }
+    }
+
+    /// Binds outgoing connections to the given local address rather than letting the OS choose.
+    pub fn set_local_address(&mut self, addr: Option<IpAddr>) {
+        self.http.set_local_address(addr);
+        self.local_address = addr;
+    }
+
+    /// Spreads outgoing connections across the addresses of several network interfaces.
+    pub fn set_interfaces(&mut self, interfaces: Option<Arc<InterfaceAddresses>>) {
+        self.interfaces = interfaces;
+    }
+
+    /// Turns on TCP keep-alive probes for outgoing connections.
+    pub fn set_tcp_keepalive(&mut self, keepalive: Option<TcpKeepalive>) {
+        self.tcp_keepalive = keepalive;
+    }
+
+    /// Drops outgoing connections whose sent data goes unacknowledged for this long.
+    pub fn set_tcp_user_timeout(&mut self, timeout: Option<Duration>) {
+        self.tcp_user_timeout = timeout;
+    }
+
+    /// A connector that starts each connection with a PROXY protocol header for `source`.
+    pub fn with_proxy_header(&self, version: ProxyProtocol, source: SocketAddr) -> Connector {
+        let mut connector = self.clone();
+        connector.proxy_header = Some((version, source));
+        connector
+    }
+}
+
+impl Connect for Connector {
+    type Transport = MaybeTlsStream;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = (MaybeTlsStream, Connected), Error = io::Error> + Send>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let started = Instant::now();
+        let is_https = dst.scheme() == "https";
+        let host = dst.host().to_string();
+        let mut http = self.http.clone();
+        let mut local_address = self.local_address;
+        let mut interface = None;
+        if let Some((name, address)) = self.interfaces.as_ref().and_then(|i| i.next()) {
+            http.set_local_address(Some(address));
+            local_address = Some(address);
+            interface = self.interfaces.clone().map(|i| (i, name));
+        }
+        let connecting = http.connect(dst).map_err(move |e| {
+            if let Some((interfaces, name)) = interface {
+                if e.kind() == io::ErrorKind::AddrNotAvailable {
+                    interfaces.remove(name.as_str());
+                }
+            }
+            match local_address {
+                Some(local) => {
+                    io::Error::new(e.kind(), format!("{} (from local address {})", e, local))
+                }
+                None => e,
+            }
+        });
+        let keepalive = self.tcp_keepalive;
+        let user_timeout = self.tcp_user_timeout;
+        let connecting: Box<dyn Future<Item = _, Error = io::Error> + Send> =
+            if keepalive.is_some() || user_timeout.is_some() {
+                Box::new(connecting.and_then(move |(tcp, connected)| {
+                    if let Some(keepalive) = keepalive {
+                        keepalive.apply(&tcp)?;
+                    }
+                    set_user_timeout(&tcp, user_timeout)?;
+                    Ok((tcp, connected))
+                }))
+            } else {
+                Box::new(connecting)
+            };
+        let connecting: Box<dyn Future<Item = _, Error = io::Error> + Send> = match self
+            .proxy_header
+        {
+            Some((version, source)) => Box::new(connecting.and_then(move |(tcp, connected)| {
+                let header = tcp
+                    .peer_addr()
+                    .map(|destination| version.header(source, destination));
+                future::result(header)
+                    .and_then(move |header| write_all(tcp, header))
+                    .map(move |(tcp, _)| (tcp, connected))
+            })),
+            None => Box::new(connecting),
+        };
+        if !is_https {
+            return Box::new(connecting.map(move |(tcp, connected)| {
+                let connected = connected.extra(ConnectTiming::since(started));
+                (MaybeTlsStream::Plain(tcp), connected)
+            }));
+        }
+        let connector = TlsConnector::from(self.tls.read().unwrap().clone());
+        Box::new(connecting.and_then(move |(tcp, connected)| {
+            // rustls can only verify DNS names, so an IP address target gets a name that
+            // never matches; it is only usable when host name verification is turned off
+            let name = if host.parse::<IpAddr>().is_ok() {
+                "ip-address.invalid"
+            } else {
+                host.as_str()
+            };
+            let domain = match DNSNameRef::try_from_ascii_str(name) {
+                Ok(domain) => domain,
+                Err(_) => {
+                    return future::Either::A(future::err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid DNS name: {}", host),
+                    )))
+                }
+            };
+            future::Either::B(connector.connect(domain, tcp).map(move |tls| {
+                let connected = connected.extra(ConnectTiming::since(started));
+                (MaybeTlsStream::Tls(Box::new(tls)), connected)
+            }))
+        }))
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use hyper::header::{HeaderValue, SET_COOKIE};
+use hyper::HeaderMap;
+
+/// Rewrites the `Set-Cookie` headers of upstream responses so that the cookies belong to
+/// the domain the client sees.
+#[derive(Default)]
+pub struct CookieRewrite {
+    domains: Vec<(String, String)>,
+    secure: bool,
+}
+
+impl CookieRewrite {
+    /// Replaces `Domain=FROM` with `Domain=TO` given a `FROM:TO` specification.
+    pub fn add_domain(&mut self, spec: &str) -> Result<(), String> {
+        let mut parts = spec.splitn(2, ':');
+        let from = parts.next().unwrap_or("").trim();
+        let to = parts.next().unwrap_or("").trim();
+        if from.is_empty() || to.is_empty() {
+            return Err(format!("Expected FROM_DOMAIN:TO_DOMAIN but got '{}'", spec));
+        }
+        self.domains.push((from.to_string(), to.to_string()));
+        Ok(())
+    }
+
+    /// Adds the `Secure` attribute to every cookie.
+    pub fn set_secure(&mut self, secure: bool) {
+        self.secure = secure;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty() && !self.secure
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap<HeaderValue>) {
+        if !headers.contains_key(SET_COOKIE) {
+            return;
+        }
+        let cookies = headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|value| match value.to_str() {
+                Ok(cookie) => {
+                    HeaderValue::from_str(&self.rewrite(cookie)).unwrap_or_else(|_| value.clone())
+                }
+                Err(_) => value.clone(),
+            })
+            .collect::<Vec<_>>();
+        headers.remove(SET_COOKIE);
+        for cookie in cookies {
+            headers.append(SET_COOKIE, cookie);
+        }
+    }
+
+    fn rewrite(&self, cookie: &str) -> String {
+        let mut attributes = cookie.split(';');
+        // the first part is the cookie itself, the rest are its attributes
+        let mut rewritten = vec![attributes.next().unwrap_or("").to_string()];
+        rewritten.extend(attributes.map(|attribute| {
+            let mut parts = attribute.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim();
+            match parts.next() {
+                Some(domain) if name.eq_ignore_ascii_case("domain") => {
+                    // a leading dot is ignored when matching
+                    let bare = domain.trim().trim_start_matches('.');
+                    match self
+                        .domains
+                        .iter()
+                        .find(|(from, _)| from.trim_start_matches('.').eq_ignore_ascii_case(bare))
+                    {
+                        Some((_, to)) => format!(" {}={}", name, to),
+                        None => attribute.to_string(),
+                    }
+                }
+                _ => attribute.to_string(),
+            }
+        }));
+        if self.secure
+            && !rewritten[1..]
+                .iter()
+                .any(|a| a.trim().eq_ignore_ascii_case("secure"))
+        {
+            rewritten.push(" Secure".to_string());
+        }
+        rewritten.join(";")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(domains: &[&str], secure: bool) -> CookieRewrite {
+        let mut rewrite = CookieRewrite::default();
+        for spec in domains {
+            rewrite.add_domain(spec).unwrap();
+        }
+        rewrite.set_secure(secure);
+        rewrite
+    }
+
+    #[test]
+    fn rewrites_matching_domains_ignoring_case_and_leading_dots() {
+        let rewrite = rewrite(&["internal.local:example.com"], false);
+        assert_eq!(
+            rewrite.rewrite("id=1; Path=/; Domain=.Internal.Local; HttpOnly"),
+            "id=1; Path=/; Domain=example.com; HttpOnly"
+        );
+        assert_eq!(
+            rewrite.rewrite("id=1; Domain=other.local"),
+            "id=1; Domain=other.local"
+        );
+    }
+
+    #[test]
+    fn adds_secure_unless_present() {
+        let rewrite = rewrite(&[], true);
+        assert_eq!(rewrite.rewrite("id=1; Path=/"), "id=1; Path=/; Secure");
+        assert_eq!(rewrite.rewrite("id=1; secure"), "id=1; secure");
+    }
+
+    #[test]
+    fn leaves_the_cookie_value_alone() {
+        let rewrite = rewrite(&["a:b"], false);
+        assert_eq!(rewrite.rewrite("domain=a"), "domain=a");
+    }
+
+    #[test]
+    fn rejects_incomplete_domain_specifications() {
+        assert!(CookieRewrite::default()
+            .add_domain("internal.local")
+            .is_err());
+        assert!(CookieRewrite::default().add_domain(":example.com").is_err());
+    }
+}
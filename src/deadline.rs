@@ -0,0 +1,76 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::HeaderMap;
+
+use crate::grpc;
+
+/// The header that tells a non-gRPC upstream how long it has left.
+const REMAINING_HEADER: &str = "X-Proxy-Deadline-Remaining-Ms";
+
+/// How long is left until the RFC 3339 deadline in the `name` header, or `None` if there is
+/// no valid deadline. A deadline that has passed leaves no time at all.
+pub fn remaining(headers: &HeaderMap, name: &HeaderName) -> Option<Duration> {
+    let value = headers.get(name)?.to_str().ok()?;
+    let deadline = DateTime::parse_from_rfc3339(value.trim()).ok()?;
+    let remaining = deadline.with_timezone(&Utc) - Utc::now();
+    Some(remaining.to_std().unwrap_or_default())
+}
+
+/// Tells the upstream how long it has left: as the `grpc-timeout` of a gRPC call, unless it
+/// already has a shorter one, and otherwise in the `X-Proxy-Deadline-Remaining-Ms` header.
+pub fn propagate(headers: &mut HeaderMap, remaining: Duration) {
+    if grpc::is_grpc(headers) {
+        grpc::cap_timeout(headers, remaining);
+    } else {
+        headers.insert(
+            REMAINING_HEADER,
+            HeaderValue::from(remaining.as_millis() as u64),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deadline(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Deadline", HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn remaining_of(headers: &HeaderMap) -> Option<Duration> {
+        remaining(headers, &HeaderName::from_static("x-deadline"))
+    }
+
+    #[test]
+    fn time_left_until_a_future_deadline() {
+        let at = (Utc::now() + chrono::Duration::seconds(30)).to_rfc3339();
+        let left = remaining_of(&deadline(at.as_str())).unwrap();
+        assert!(left > Duration::from_secs(29) && left <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn a_past_deadline_leaves_no_time() {
+        assert_eq!(
+            remaining_of(&deadline("2001-02-03T04:05:06+07:00")),
+            Some(Duration::from_secs(0))
+        );
+    }
+
+    #[test]
+    fn no_deadline_without_a_valid_header() {
+        assert_eq!(remaining_of(&HeaderMap::new()), None);
+        assert_eq!(remaining_of(&deadline("in five minutes")), None);
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Future};
+use hyper::client::connect::dns::{GaiResolver, Name, Resolve};
+use trust_dns_resolver::config::{
+    NameServerConfig, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts,
+};
+use trust_dns_resolver::AsyncResolver;
+
+type Cache = HashMap<String, (Instant, Vec<IpAddr>)>;
+type Background = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+/// Parses a DNS server address, `IP[:PORT]` for UDP or `tcp://IP[:PORT]` for TCP.
+pub fn parse_server(spec: &str) -> Result<NameServerConfig, String> {
+    let (protocol, address) = match spec.strip_prefix("tcp://") {
+        Some(address) => (Protocol::Tcp, address),
+        None => (Protocol::Udp, spec.strip_prefix("udp://").unwrap_or(spec)),
+    };
+    let socket_addr = match address.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(_) => match address.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, 53),
+            Err(_) => return Err(format!("Invalid DNS server address '{}'", spec)),
+        },
+    };
+    Ok(NameServerConfig {
+        socket_addr,
+        protocol,
+        tls_dns_name: None,
+    })
+}
+
+#[derive(Clone)]
+enum Inner {
+    System(GaiResolver),
+    Servers {
+        resolver: AsyncResolver,
+        /// The task that talks to the DNS servers, spawned by the first lookup.
+        background: Arc<Mutex<Option<Background>>>,
+        description: Arc<String>,
+    },
+}
+
+/// Resolves upstream host names, remembering the answers for `ttl`.
+///
+/// Names are looked up with `getaddrinfo` unless specific DNS servers are given.
+/// A `ttl` of zero disables the cache so every new connection resolves the name again.
+#[derive(Clone)]
+pub struct CachingResolver {
+    inner: Inner,
+    ttl: Duration,
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl CachingResolver {
+    pub fn new(ttl: Duration) -> CachingResolver {
+        CachingResolver {
+            inner: Inner::System(GaiResolver::new(4)),
+            ttl,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Resolves names by asking the given DNS servers directly rather than the system.
+    ///
+    /// Names are looked up as given, without the search domains of `/etc/resolv.conf`.
+    pub fn with_servers(ttl: Duration, servers: Vec<NameServerConfig>) -> CachingResolver {
+        let description = servers
+            .iter()
+            .map(|s| match s.protocol {
+                Protocol::Tcp => format!("tcp://{}", s.socket_addr),
+                _ => s.socket_addr.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut group = NameServerConfigGroup::new();
+        for server in servers {
+            group.push(server);
+        }
+        let config = ResolverConfig::from_parts(None, Vec::new(), group);
+        let (resolver, background) = AsyncResolver::new(config, ResolverOpts::default());
+        CachingResolver {
+            inner: Inner::Servers {
+                resolver,
+                background: Arc::new(Mutex::new(Some(Box::new(background)))),
+                description: Arc::new(description),
+            },
+            ttl,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn lookup(&self, name: Name) -> Box<dyn Future<Item = Vec<IpAddr>, Error = io::Error> + Send> {
+        match &self.inner {
+            Inner::System(resolver) => {
+                Box::new(resolver.resolve(name).map(|addrs| addrs.collect()))
+            }
+            Inner::Servers {
+                resolver,
+                background,
+                description,
+            } => {
+                if let Some(background) = background.lock().unwrap().take() {
+                    hyper::rt::spawn(background);
+                }
+                let description = description.clone();
+                let host = name.as_str().to_string();
+                Box::new(
+                    resolver
+                        .lookup_ip(name.as_str())
+                        .map(|lookup| lookup.iter().collect())
+                        .map_err(move |e| {
+                            io::Error::other(format!(
+                                "could not resolve {} with DNS servers {}: {}",
+                                host, description, e
+                            ))
+                        }),
+                )
+            }
+        }
+    }
+}
+
+impl Resolve for CachingResolver {
+    type Addrs = std::vec::IntoIter<IpAddr>;
+    type Future = Box<dyn Future<Item = Self::Addrs, Error = io::Error> + Send>;
+
+    fn resolve(&self, name: Name) -> Self::Future {
+        let host = name.as_str().to_string();
+        if self.ttl > Duration::from_secs(0) {
+            if let Some((resolved, addrs)) = self.cache.lock().unwrap().get(&host) {
+                if resolved.elapsed() < self.ttl {
+                    return Box::new(future::ok(addrs.clone().into_iter()));
+                }
+            }
+        }
+        let ttl = self.ttl;
+        let cache = self.cache.clone();
+        Box::new(self.lookup(name).map(move |addrs| {
+            if ttl > Duration::from_secs(0) && !addrs.is_empty() {
+                cache
+                    .lock()
+                    .unwrap()
+                    .insert(host, (Instant::now(), addrs.clone()));
+            }
+            addrs.into_iter()
+        }))
+    }
+}
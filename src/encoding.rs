@@ -0,0 +1,282 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::sync::{Arc, RwLock};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::{self, Future};
+use futures::Stream;
+use hyper::header::{
+    HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY,
+};
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+
+use crate::body::{self, BoundedBody, BoundedWriter};
+use crate::errors::{ErrorFormat, ErrorStyle};
+use crate::logging::RequestTag;
+use crate::BoxFut;
+
+/// Whether an `Accept-Encoding` header allows the given content coding.
+pub fn accepts(accept_encoding: Option<&HeaderValue>, coding: &str) -> bool {
+    let value = match accept_encoding.and_then(|v| v.to_str().ok()) {
+        Some(value) => value,
+        None => return false,
+    };
+    for item in value.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or("").trim();
+        if !name.eq_ignore_ascii_case(coding) && name != "*" {
+            continue;
+        }
+        let q = params
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        return q > 0.0;
+    }
+    false
+}
+
+/// How large a brotli response may be, both as read from the upstream and once decompressed,
+/// when `--upstream-max-response-size` does not say.
+pub const DEFAULT_MAX_DECODED_SIZE: u64 = 64 * 1024 * 1024;
+
+fn is_brotli<B>(response: &Response<B>) -> bool {
+    response
+        .headers()
+        .get(CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("br"))
+}
+
+fn decode(content: &[u8], gzip: bool, limit: u64) -> io::Result<Vec<u8>> {
+    let mut decoded = BoundedWriter::new(limit);
+    brotli::BrotliDecompress(&mut &content[..], &mut decoded)?;
+    let decoded = decoded.into_inner();
+    if !gzip {
+        return Ok(decoded);
+    }
+    gzip_encode(&decoded)
+}
+
+/// Adds `Accept-Encoding` to the `Vary` header of a response whose encoding now depends on it.
+fn vary_on_accept_encoding(headers: &mut HeaderMap) {
+    let varies = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| v.trim())
+        .any(|v| v == "*" || v.eq_ignore_ascii_case("accept-encoding"));
+    if !varies {
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+/// Decompresses a brotli encoded response, re-encoding it with gzip if `gzip` is set.
+///
+/// Responses that are not brotli encoded are passed through untouched. A response that is more
+/// than `limit` bytes either as read or once decompressed is replaced with a 502, as a small
+/// brotli body can expand to a huge one.
+pub fn decode_brotli(
+    response: Response<Body>,
+    gzip: bool,
+    limit: u64,
+    tag: RequestTag,
+    style: ErrorStyle,
+) -> BoxFut {
+    if !is_brotli(&response) {
+        return Box::new(future::ok(response));
+    }
+    let (mut parts, body) = response.into_parts();
+    Box::new(
+        BoundedBody::new(body, limit)
+            .concat2()
+            .then(move |content| {
+                let content = match content {
+                    Ok(content) => content,
+                    Err(e) => {
+                        log_error!("{}Error: brotli upstream response {}", tag, e);
+                        return Ok(if e.is::<body::BodyTooLarge>() {
+                            body::upstream_response_too_large(&style)
+                        } else {
+                            style.response(
+                                StatusCode::BAD_GATEWAY,
+                                "the upstream connection failed",
+                                ErrorFormat::Text,
+                            )
+                        });
+                    }
+                };
+                Ok(match decode(&content, gzip, limit) {
+                    Ok(content) => {
+                        if gzip {
+                            parts
+                                .headers
+                                .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+                        } else {
+                            parts.headers.remove(CONTENT_ENCODING);
+                        }
+                        parts.headers.insert(CONTENT_LENGTH, content.len().into());
+                        vary_on_accept_encoding(&mut parts.headers);
+                        Response::from_parts(parts, Body::from(content))
+                    }
+                    Err(ref e) if body::is_too_large(e) => {
+                        log_error!("{}Error: decompressed upstream response {}", tag, e);
+                        body::upstream_response_too_large(&style)
+                    }
+                    Err(e) => {
+                        log_error!("{}Error: invalid brotli response: {}", tag, e);
+                        style.response(
+                            StatusCode::BAD_GATEWAY,
+                            "the upstream response is not valid brotli",
+                            ErrorFormat::Text,
+                        )
+                    }
+                })
+            }),
+    )
+}
+
+fn is_compressible<B>(req: &Request<B>) -> bool {
+    if req.headers().contains_key(CONTENT_ENCODING) {
+        return false;
+    }
+    let content_type = match req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+    {
+        Some(v) => v
+            .split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .to_ascii_lowercase(),
+        None => return false,
+    };
+    content_type == "application/json"
+        || content_type == "application/x-www-form-urlencoded"
+        || content_type.starts_with("text/")
+}
+
+fn gzip_encode(content: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(content)?;
+    encoder.finish()
+}
+
+/// Gzips request bodies for the upstreams that accept gzip encoded requests.
+///
+/// An upstream advertises this with an `Accept-Encoding` header in its responses, so the
+/// requests to it are only compressed once it has answered one.
+pub struct RequestCompression {
+    min_bytes: usize,
+    accepted: RwLock<HashSet<String>>,
+}
+
+impl RequestCompression {
+    pub fn new(min_bytes: usize) -> RequestCompression {
+        RequestCompression {
+            min_bytes,
+            accepted: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Remembers whether the upstream said that it accepts gzip encoded requests.
+    pub fn learn<B>(&self, upstream: &str, response: &Response<B>) {
+        let accepts_gzip = accepts(response.headers().get(ACCEPT_ENCODING), "gzip");
+        if accepts_gzip != self.accepted.read().unwrap().contains(upstream) {
+            let mut accepted = self.accepted.write().unwrap();
+            if accepts_gzip {
+                accepted.insert(upstream.to_string());
+            } else if response.headers().contains_key(ACCEPT_ENCODING) {
+                accepted.remove(upstream);
+            }
+        }
+    }
+
+    /// Whether the request body should be compressed on its way to the upstream.
+    pub fn applies<B>(&self, upstream: &str, req: &Request<B>) -> bool {
+        is_compressible(req) && self.accepted.read().unwrap().contains(upstream)
+    }
+
+    /// Compresses the request body if it is at least the minimum size.
+    ///
+    /// Resolves to `None` if the body is more than `limit` bytes.
+    pub fn compress(
+        self: Arc<Self>,
+        req: Request<Body>,
+        limit: u64,
+    ) -> impl Future<Item = Option<Request<Body>>, Error = hyper::Error> {
+        body::buffer_request(req, limit).map(move |buffered| {
+            let (mut parts, content) = buffered?;
+            if content.len() < self.min_bytes {
+                return Some(Request::from_parts(parts, Body::from(content)));
+            }
+            Some(match gzip_encode(&content) {
+                Ok(compressed) => {
+                    parts
+                        .headers
+                        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+                    parts
+                        .headers
+                        .insert(CONTENT_LENGTH, compressed.len().into());
+                    Request::from_parts(parts, Body::from(compressed))
+                }
+                Err(_) => Request::from_parts(parts, Body::from(content)),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn brotli(content: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+            writer.write_all(content).unwrap();
+        }
+        compressed
+    }
+
+    #[test]
+    fn decodes_within_the_limit() {
+        let content = vec![b'a'; 1000];
+        assert_eq!(decode(&brotli(&content), false, 1000).unwrap(), content);
+    }
+
+    #[test]
+    fn stops_decoding_past_the_limit() {
+        let error = decode(&brotli(&vec![0; 1 << 20]), false, 1000).unwrap_err();
+        assert!(body::is_too_large(&error));
+        let error = decode(b"not brotli", false, 1000).unwrap_err();
+        assert!(!body::is_too_large(&error));
+    }
+
+    #[test]
+    fn adds_accept_encoding_to_vary_once() {
+        let mut headers = HeaderMap::new();
+        headers.insert(VARY, HeaderValue::from_static("Origin"));
+        vary_on_accept_encoding(&mut headers);
+        vary_on_accept_encoding(&mut headers);
+        let vary: Vec<_> = headers.get_all(VARY).iter().collect();
+        assert_eq!(vary, vec!["Origin", "Accept-Encoding"]);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(VARY, HeaderValue::from_static("*"));
+        vary_on_accept_encoding(&mut headers);
+        assert_eq!(headers.get_all(VARY).iter().count(), 1);
+    }
+}
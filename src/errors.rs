@@ -0,0 +1,150 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::str::FromStr;
+
+use hyper::header::{ACCEPT, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+
+use crate::logging::RequestTag;
+
+/// The body of the error responses that we make ourselves.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorFormat {
+    Json,
+    Html,
+    Text,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ErrorFormat, String> {
+        match s {
+            "json" => Ok(ErrorFormat::Json),
+            "html" => Ok(ErrorFormat::Html),
+            "text" => Ok(ErrorFormat::Text),
+            _ => Err(format!(
+                "Unknown error response format '{}', expected one of: json, html, text",
+                s
+            )),
+        }
+    }
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// An error response of our own, as opposed to one from the upstream.
+pub fn proxy_error_response(
+    status: StatusCode,
+    message: &str,
+    format: ErrorFormat,
+    request_id: Option<&str>,
+) -> Response<Body> {
+    let reason = status.canonical_reason().unwrap_or("Error");
+    let (content_type, body) = match format {
+        ErrorFormat::Json => {
+            let body = serde_json::json!({
+                "error": message,
+                "code": status.as_u16(),
+                "request_id": request_id,
+            });
+            ("application/json", body.to_string())
+        }
+        ErrorFormat::Html => (
+            "text/html; charset=utf-8",
+            format!(
+                "
+            <!DOCTYPE html>
+            <html>
+            <head>
+            <title>{reason}</title>
+            <style>
+                body {{
+                    width: 35em;
+                    margin: 0 auto;
+                    font-family: Tahoma, Verdana, Arial, sans-serif;
+                }}
+            </style>
+            </head>
+            <body>
+            <h1>{reason}</h1>
+            <p>{message}</p>
+            </body>
+            </html>
+        ",
+                reason = reason,
+                message = escape_html(message)
+            ),
+        ),
+        ErrorFormat::Text => ("text/plain; charset=utf-8", format!("{}\n", message)),
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// How to make our own error responses to a request.
+///
+/// It is carried in the request extensions, like the `RequestTag`, so that errors raised
+/// while proxying look the same as those raised before.
+#[derive(Clone, Default)]
+pub struct ErrorStyle {
+    /// `None` leaves the format to each kind of error.
+    format: Option<ErrorFormat>,
+    request_id: Option<String>,
+}
+
+impl ErrorStyle {
+    /// The `configured` format, or JSON for clients that accept it.
+    pub fn new<B>(configured: Option<ErrorFormat>, req: &Request<B>) -> ErrorStyle {
+        let accepts_json = req
+            .headers()
+            .get_all(ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.contains("application/json"));
+        ErrorStyle {
+            format: configured.or(if accepts_json {
+                Some(ErrorFormat::Json)
+            } else {
+                None
+            }),
+            request_id: RequestTag::of(req).id().map(str::to_string),
+        }
+    }
+
+    pub fn of<B>(req: &Request<B>) -> ErrorStyle {
+        req.extensions()
+            .get::<ErrorStyle>()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// An error response in this style, or in the `default` format if none was chosen.
+    pub fn response(
+        &self,
+        status: StatusCode,
+        message: &str,
+        default: ErrorFormat,
+    ) -> Response<Body> {
+        proxy_error_response(
+            status,
+            message,
+            self.format.unwrap_or(default),
+            self.request_id.as_deref(),
+        )
+    }
+}
@@ -0,0 +1,41 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Future};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+use tokio_timer::Delay;
+
+use crate::BoxFut;
+
+/// A static response returned in place of calling the upstream, for testing.
+pub struct FakeUpstream {
+    pub status: StatusCode,
+    pub body: String,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    /// How long to wait before responding, to simulate a slow upstream.
+    pub delay: Option<Duration>,
+}
+
+impl FakeUpstream {
+    pub fn respond(&self) -> BoxFut {
+        let mut response = Response::new(Body::from(self.body.clone()));
+        *response.status_mut() = self.status;
+        for (name, value) in &self.headers {
+            response.headers_mut().append(name.clone(), value.clone());
+        }
+        match self.delay {
+            Some(delay) => {
+                Box::new(Delay::new(Instant::now() + delay).then(move |_| future::ok(response)))
+            }
+            None => Box::new(future::ok(response)),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::net::IpAddr;
+
+use hyper::{HeaderMap, Request};
+
+/// The IP address of the client that sent the request, as far as we can trust it.
+///
+/// It is carried in the request extensions, like the `RequestTag`.
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    /// Works out the client IP by trusting the last `trusted_proxies` hops of `X-Forwarded-For`.
+    ///
+    /// With no trusted proxies this is just the peer address. Each trusted proxy lets us step
+    /// one entry further back along the chain, stopping early at anything that is not an IP.
+    pub fn resolve(peer: IpAddr, headers: &HeaderMap, trusted_proxies: usize) -> ClientIp {
+        let mut ip = peer;
+        if trusted_proxies == 0 {
+            return ClientIp(ip);
+        }
+        let chain: Vec<&str> = headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|v| v.trim())
+            .collect();
+        for hop in chain.iter().rev().take(trusted_proxies) {
+            match hop.parse::<IpAddr>() {
+                Ok(hop) => ip = hop,
+                Err(_) => break,
+            }
+        }
+        ClientIp(ip)
+    }
+
+    pub fn of<B>(req: &Request<B>) -> Option<IpAddr> {
+        req.extensions().get::<ClientIp>().map(|ip| ip.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn forwarded_for(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("X-Forwarded-For", HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn without_trusted_proxies_the_peer_is_the_client() {
+        let headers = forwarded_for(&["203.0.113.7"]);
+        assert_eq!(
+            ClientIp::resolve(ip("10.0.0.1"), &headers, 0).0,
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn each_trusted_proxy_steps_one_hop_back() {
+        let headers = forwarded_for(&["203.0.113.7, 198.51.100.2", "10.0.0.2"]);
+        let peer = ip("10.0.0.1");
+        assert_eq!(ClientIp::resolve(peer, &headers, 1).0, ip("10.0.0.2"));
+        assert_eq!(ClientIp::resolve(peer, &headers, 2).0, ip("198.51.100.2"));
+        assert_eq!(ClientIp::resolve(peer, &headers, 3).0, ip("203.0.113.7"));
+        assert_eq!(ClientIp::resolve(peer, &headers, 10).0, ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn stops_at_a_hop_that_is_not_an_ip() {
+        let headers = forwarded_for(&["203.0.113.7, unknown, 2001:db8::1"]);
+        let peer = ip("10.0.0.1");
+        assert_eq!(ClientIp::resolve(peer, &headers, 3).0, ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn without_the_header_the_peer_is_the_client() {
+        let peer = ip("10.0.0.1");
+        assert_eq!(ClientIp::resolve(peer, &HeaderMap::new(), 2).0, peer);
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+/// A path pattern where `*` matches within one path segment, `**` matches across segments
+/// and `?` matches a single character other than `/`.
+pub struct Glob(Vec<u8>);
+
+impl Glob {
+    pub fn new(pattern: &str) -> Glob {
+        Glob(pattern.as_bytes().to_vec())
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        matches(&self.0, path.as_bytes())
+    }
+}
+
+fn matches(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern.first() {
+        None => path.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            (0..=path.len()).any(|i| matches(rest, &path[i..]))
+        }
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            let segment = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+            (0..=segment).any(|i| matches(rest, &path[i..]))
+        }
+        Some(b'?') => match path.first() {
+            Some(&c) if c != b'/' => matches(&pattern[1..], &path[1..]),
+            _ => false,
+        },
+        Some(&c) => path.first() == Some(&c) && matches(&pattern[1..], &path[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_patterns_match_only_themselves() {
+        let glob = Glob::new("/api/health");
+        assert!(glob.matches("/api/health"));
+        assert!(!glob.matches("/api/health/"));
+        assert!(!glob.matches("/api/healthz"));
+    }
+
+    #[test]
+    fn star_matches_within_a_segment() {
+        let glob = Glob::new("/api/*/status");
+        assert!(glob.matches("/api/orders/status"));
+        assert!(glob.matches("/api//status"));
+        assert!(!glob.matches("/api/orders/42/status"));
+    }
+
+    #[test]
+    fn double_star_matches_across_segments() {
+        let glob = Glob::new("/static/**");
+        assert!(glob.matches("/static/"));
+        assert!(glob.matches("/static/js/app.js"));
+        assert!(!glob.matches("/api/static/app.js"));
+        assert!(Glob::new("/**/*.js").matches("/static/js/app.js"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character_but_not_a_slash() {
+        let glob = Glob::new("/v?/items");
+        assert!(glob.matches("/v1/items"));
+        assert!(!glob.matches("/v/items"));
+        assert!(!glob.matches("/v12/items"));
+        assert!(!Glob::new("/a?b").matches("/a/b"));
+    }
+}
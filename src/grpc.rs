@@ -0,0 +1,161 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::Duration;
+
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, HeaderMap, Response};
+
+/// The gRPC status code for a call that ran out of time.
+const DEADLINE_EXCEEDED: &str = "4";
+
+/// The deadline from a `grpc-timeout` header, e.g. `5S` or `250m`.
+pub fn timeout(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get("grpc-timeout")?.to_str().ok()?;
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount = amount.parse::<u64>().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 60 * 60)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+/// Whether the headers are those of a gRPC call.
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/grpc"))
+}
+
+/// Lowers the `grpc-timeout` of a gRPC call to at most `max`, adding one if it has none.
+pub fn cap_timeout(headers: &mut HeaderMap, max: Duration) {
+    if timeout(headers).is_some_and(|timeout| timeout <= max) {
+        return;
+    }
+    headers.insert(
+        "grpc-timeout",
+        HeaderValue::from_str(format_timeout(max).as_str()).unwrap(),
+    );
+}
+
+/// The largest amount in a `grpc-timeout`, which is limited to eight digits.
+const MAX_TIMEOUT_AMOUNT: u128 = 99_999_999;
+
+/// A `grpc-timeout` value for at most `timeout`, in milliseconds unless that needs more
+/// digits than allowed, when it is rounded down to a coarser unit.
+fn format_timeout(timeout: Duration) -> String {
+    let millis = timeout.as_millis();
+    [
+        ("m", 1),
+        ("S", 1000),
+        ("M", 60 * 1000),
+        ("H", 60 * 60 * 1000),
+    ]
+    .iter()
+    .map(|&(unit, per)| (millis / per, unit))
+    .find(|&(amount, _)| amount <= MAX_TIMEOUT_AMOUNT)
+    .map(|(amount, unit)| format!("{}{}", amount, unit))
+    .unwrap_or_else(|| format!("{}H", MAX_TIMEOUT_AMOUNT))
+}
+
+/// A trailers-only gRPC response telling the client that its deadline was exceeded.
+///
+/// We cannot send HTTP/2 trailers ourselves, but the gRPC protocol allows the status to be
+/// sent in the headers of a response without a body instead.
+pub fn deadline_exceeded() -> Response<Body> {
+    Response::builder()
+        .status(200)
+        .header(CONTENT_TYPE, "application/grpc")
+        .header("grpc-status", DEADLINE_EXCEEDED)
+        .header("grpc-message", "Deadline exceeded waiting for the upstream")
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn capped(max: Duration) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+        cap_timeout(&mut headers, max);
+        timeout(&headers)
+    }
+
+    fn timeout_of(value: &str) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        headers.insert("grpc-timeout", HeaderValue::from_str(value).unwrap());
+        timeout(&headers)
+    }
+
+    #[test]
+    fn timeout_parses_every_unit() {
+        assert_eq!(timeout_of("2H"), Some(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(timeout_of("3M"), Some(Duration::from_secs(3 * 60)));
+        assert_eq!(timeout_of("5S"), Some(Duration::from_secs(5)));
+        assert_eq!(timeout_of("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(timeout_of("10u"), Some(Duration::from_micros(10)));
+        assert_eq!(
+            timeout_of("99999999n"),
+            Some(Duration::from_nanos(99_999_999))
+        );
+    }
+
+    #[test]
+    fn timeout_rejects_invalid_values() {
+        assert_eq!(timeout(&HeaderMap::new()), None);
+        assert_eq!(timeout_of("S"), None);
+        assert_eq!(timeout_of("5s"), None);
+        assert_eq!(timeout_of("-5S"), None);
+        assert_eq!(timeout_of("100000000m"), None);
+    }
+
+    #[test]
+    fn cap_timeout_keeps_a_shorter_timeout() {
+        let mut headers = HeaderMap::new();
+        headers.insert("grpc-timeout", HeaderValue::from_static("1S"));
+        cap_timeout(&mut headers, Duration::from_secs(5));
+        assert_eq!(headers["grpc-timeout"], "1S");
+        cap_timeout(&mut headers, Duration::from_millis(200));
+        assert_eq!(headers["grpc-timeout"], "200m");
+    }
+
+    #[test]
+    fn cap_timeout_uses_milliseconds_when_they_fit() {
+        assert_eq!(
+            capped(Duration::from_millis(99_999_999)),
+            Some(Duration::from_millis(99_999_999))
+        );
+    }
+
+    #[test]
+    fn cap_timeout_rounds_long_timeouts_down_to_a_coarser_unit() {
+        assert_eq!(
+            capped(Duration::from_millis(100_000_000)),
+            Some(Duration::from_secs(100_000))
+        );
+        assert_eq!(
+            capped(Duration::from_millis(100_000_999)),
+            Some(Duration::from_secs(100_000))
+        );
+        assert_eq!(
+            capped(Duration::from_secs(100_000_000 * 60 * 60)),
+            Some(Duration::from_secs(99_999_999 * 60 * 60))
+        );
+    }
+}
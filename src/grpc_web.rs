@@ -0,0 +1,192 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::error::Error;
+
+use futures::{try_ready, Async, Poll, Stream};
+use hyper::body::Payload;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Chunk, HeaderMap, Request, Response};
+
+use crate::grpc;
+
+/// The flag of the frame that gRPC-Web sends the trailers in, at the end of the body.
+const TRAILERS_FRAME: u8 = 0x80;
+
+/// How a gRPC-Web call encodes its bodies.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// `application/grpc-web`, the gRPC framing as is.
+    Binary,
+    /// `application/grpc-web-text`, the gRPC framing in base64.
+    Text,
+}
+
+impl Format {
+    /// The format of a gRPC-Web call, or `None` if the headers are not those of one.
+    pub fn of(headers: &HeaderMap) -> Option<Format> {
+        let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+        let (format, _) = split_content_type(content_type)?;
+        Some(format)
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Binary => "application/grpc-web",
+            Format::Text => "application/grpc-web-text",
+        }
+    }
+
+    fn encode(self, data: Chunk) -> Chunk {
+        match self {
+            Format::Binary => data,
+            Format::Text => base64::encode(&data).into(),
+        }
+    }
+}
+
+/// Splits a gRPC-Web content type into its format and the rest, e.g. `+proto`.
+fn split_content_type(content_type: &str) -> Option<(Format, &str)> {
+    if let Some(rest) = content_type.strip_prefix("application/grpc-web-text") {
+        Some((Format::Text, rest))
+    } else {
+        content_type
+            .strip_prefix("application/grpc-web")
+            .map(|rest| (Format::Binary, rest))
+    }
+}
+
+/// Turns a gRPC-Web request into the gRPC call to send upstream.
+pub fn to_grpc(req: Request<Body>, format: Format) -> Request<Body> {
+    let (mut parts, body) = req.into_parts();
+    let content_type = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(split_content_type)
+        .map(|(_, rest)| format!("application/grpc{}", rest));
+    if let Some(content_type) = content_type.and_then(|v| HeaderValue::from_str(&v).ok()) {
+        parts.headers.insert(CONTENT_TYPE, content_type);
+    }
+    let body = match format {
+        Format::Binary => body,
+        Format::Text => {
+            parts.headers.remove(CONTENT_LENGTH);
+            Body::wrap_stream(Base64Decode {
+                inner: body,
+                pending: Vec::new(),
+            })
+        }
+    };
+    Request::from_parts(parts, body)
+}
+
+/// Turns the upstream's response to a gRPC call into the gRPC-Web response for the client.
+///
+/// Responses that are not gRPC, such as our own errors, are left alone.
+pub fn to_grpc_web(response: Response<Body>, format: Format) -> Response<Body> {
+    if !grpc::is_grpc(response.headers()) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let content_type = parts
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("application/grpc"))
+        .map(|rest| format!("{}{}", format.content_type(), rest));
+    if let Some(content_type) = content_type.and_then(|v| HeaderValue::from_str(&v).ok()) {
+        parts.headers.insert(CONTENT_TYPE, content_type);
+    }
+    parts.headers.remove(CONTENT_LENGTH);
+    let body = GrpcWebBody {
+        inner: body,
+        format,
+        done: false,
+    };
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// The frame that carries the trailers to a gRPC-Web client, as browsers cannot read them.
+fn trailers_frame(trailers: &HeaderMap) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in trailers.iter() {
+        block.extend_from_slice(name.as_str().as_bytes());
+        block.push(b':');
+        block.extend_from_slice(value.as_bytes());
+        block.extend_from_slice(b"\r\n");
+    }
+    let mut frame = Vec::with_capacity(5 + block.len());
+    frame.push(TRAILERS_FRAME);
+    frame.extend_from_slice(&(block.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&block);
+    frame
+}
+
+/// A gRPC-Web text request body, decoded back to the gRPC framing.
+///
+/// Clients may send the messages as separately padded pieces of base64, so it is decoded a
+/// group of four characters at a time.
+struct Base64Decode {
+    inner: Body,
+    pending: Vec<u8>,
+}
+
+impl Stream for Base64Decode {
+    type Item = Chunk;
+    type Error = Box<dyn Error + Send + Sync>;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, Self::Error> {
+        loop {
+            match try_ready!(self.inner.poll()) {
+                Some(chunk) => {
+                    self.pending
+                        .extend(chunk.iter().filter(|b| !b.is_ascii_whitespace()).cloned());
+                    let whole = self.pending.len() / 4 * 4;
+                    if whole == 0 {
+                        continue;
+                    }
+                    let mut decoded = Vec::with_capacity(whole / 4 * 3);
+                    for group in self.pending[..whole].chunks(4) {
+                        decoded.extend(base64::decode(group)?);
+                    }
+                    self.pending.drain(..whole);
+                    return Ok(Async::Ready(Some(decoded.into())));
+                }
+                None if self.pending.is_empty() => return Ok(Async::Ready(None)),
+                None => return Err("gRPC-Web text request body is not whole base64".into()),
+            }
+        }
+    }
+}
+
+/// An upstream gRPC response body, with its trailers moved into the final frame.
+struct GrpcWebBody {
+    inner: Body,
+    format: Format,
+    done: bool,
+}
+
+impl Stream for GrpcWebBody {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        if let Some(chunk) = try_ready!(self.inner.poll_data()) {
+            return Ok(Async::Ready(Some(self.format.encode(chunk))));
+        }
+        let trailers = try_ready!(self.inner.poll_trailers());
+        self.done = true;
+        Ok(Async::Ready(trailers.map(|trailers| {
+            self.format.encode(trailers_frame(&trailers).into())
+        })))
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Duration, Utc};
+use hyper::header::{HeaderName, HeaderValue, CACHE_CONTROL, EXPIRES};
+use hyper::HeaderMap;
+
+/// Parses a `NAME:VALUE` header specification from the command line.
+pub fn parse_header(spec: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let mut parts = spec.splitn(2, ':');
+    let name = parts.next().unwrap_or("").trim();
+    let value = match parts.next() {
+        Some(value) => value.trim(),
+        None => return Err(format!("Expected NAME:VALUE but got '{}'", spec)),
+    };
+    let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("Invalid header name in '{}'", spec))?;
+    let value =
+        HeaderValue::from_str(value).map_err(|_| format!("Invalid header value in '{}'", spec))?;
+    Ok((name, value))
+}
+
+/// The `Authorization` header value for Basic authentication with `USER:PASSWORD` credentials.
+pub fn basic_auth(credentials: &str) -> Result<HeaderValue, String> {
+    if !credentials.contains(':') {
+        return Err("Expected basic auth credentials as USER:PASSWORD".to_string());
+    }
+    let mut value = HeaderValue::from_str(&format!("Basic {}", base64::encode(credentials)))
+        .map_err(|_| "Invalid basic auth credentials".to_string())?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+/// Lowers the `max-age` and `s-maxage` the upstream allows in `Cache-Control` to at most `cap`
+/// seconds, and brings a later `Expires` forward to `cap` seconds from now.
+///
+/// Other directives, such as `no-store` and `no-cache`, are kept as they are.
+pub fn cap_max_age(headers: &mut HeaderMap<HeaderValue>, cap: u64) {
+    let capped = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .map(|value| {
+            let value = value.to_str().ok()?;
+            let directives = value
+                .split(',')
+                .map(|directive| {
+                    let directive = directive.trim();
+                    let mut parts = directive.splitn(2, '=');
+                    let name = parts.next().unwrap_or("").trim();
+                    let seconds = parts
+                        .next()
+                        .and_then(|v| v.trim().trim_matches('"').parse::<u64>().ok());
+                    match seconds {
+                        Some(seconds)
+                            if seconds > cap
+                                && (name.eq_ignore_ascii_case("max-age")
+                                    || name.eq_ignore_ascii_case("s-maxage")) =>
+                        {
+                            format!("{}={}", name, cap)
+                        }
+                        _ => directive.to_string(),
+                    }
+                })
+                .collect::<Vec<_>>();
+            HeaderValue::from_str(&directives.join(", ")).ok()
+        })
+        .collect::<Option<Vec<_>>>();
+    if let Some(capped) = capped {
+        headers.remove(CACHE_CONTROL);
+        for value in capped {
+            headers.append(CACHE_CONTROL, value);
+        }
+    }
+    let latest = Utc::now() + Duration::seconds(cap.min(i64::MAX as u64 / 1000) as i64);
+    // an Expires that cannot be parsed already means expired
+    let expires = headers
+        .get(EXPIRES)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+        .map(|v| v.with_timezone(&Utc));
+    if expires.is_some_and(|expires| expires > latest) {
+        let latest = latest.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        headers.insert(EXPIRES, HeaderValue::from_str(&latest).unwrap());
+    }
+}
+
+/// The headers to inject into the responses returned to the client.
+#[derive(Clone, Default)]
+pub struct ResponseHeaders {
+    set: Vec<(HeaderName, HeaderValue)>,
+    set_if_missing: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl ResponseHeaders {
+    /// Always sets the header, replacing any value from the upstream.
+    pub fn set(&mut self, name: HeaderName, value: HeaderValue) {
+        self.set.push((name, value));
+    }
+
+    /// Sets the header only when the upstream did not provide it.
+    pub fn set_if_missing(&mut self, name: HeaderName, value: HeaderValue) {
+        self.set_if_missing.push((name, value));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.set_if_missing.is_empty()
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap<HeaderValue>) {
+        for (name, value) in &self.set_if_missing {
+            // an explicit set of the same header takes precedence
+            if !headers.contains_key(name) && !self.set.iter().any(|(n, _)| n == name) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+        for (name, value) in &self.set {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+}
+
+/// A header with the time that the request was sent upstream, e.g. for replay protection.
+pub struct RequestTimestamp {
+    name: HeaderName,
+    format: String,
+}
+
+impl RequestTimestamp {
+    /// RFC 3339 with milliseconds in UTC, e.g. `2024-01-15T12:00:00.000Z`.
+    pub const DEFAULT_FORMAT: &'static str = "%Y-%m-%dT%H:%M:%S%.3fZ";
+
+    /// Takes a `strftime` style `format`.
+    pub fn new(name: &str, format: &str) -> Result<RequestTimestamp, String> {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("'{}' is not a valid header name", name))?;
+        if StrftimeItems::new(format).any(|item| item == Item::Error) {
+            return Err(format!("'{}' is not a valid timestamp format", format));
+        }
+        Ok(RequestTimestamp {
+            name,
+            format: format.to_string(),
+        })
+    }
+
+    pub fn apply(&self, headers: &mut HeaderMap<HeaderValue>) {
+        let now = Utc::now().format(self.format.as_str()).to_string();
+        match HeaderValue::from_str(&now) {
+            Ok(value) => {
+                headers.insert(self.name.clone(), value);
+            }
+            Err(_) => {
+                headers.remove(&self.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::Arc;
+
+use futures::future::{self, Future};
+use futures::Stream;
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+
+use crate::BoxFut;
+
+fn is_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("text/html"))
+}
+
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Adds a `<script src="..." defer></script>` tag to HTML pages, just before `</body>`.
+pub struct ScriptTag {
+    tag: String,
+    src: String,
+}
+
+impl ScriptTag {
+    pub fn new(url: &str) -> ScriptTag {
+        let src = escape_attribute(url);
+        ScriptTag {
+            tag: format!("<script src=\"{}\" defer></script>", src),
+            src,
+        }
+    }
+
+    /// Whether the page already loads the script.
+    fn is_present(&self, page: &[u8]) -> bool {
+        find(page, format!("src=\"{}\"", self.src).as_bytes()).is_some()
+            || find(page, format!("src='{}'", self.src).as_bytes()).is_some()
+    }
+
+    /// Buffers an HTML response and adds the script tag to it.
+    ///
+    /// Anything that is not an uncompressed, complete HTML page with a `</body>`, or that
+    /// already loads the script, is returned untouched.
+    pub fn inject(self: Arc<Self>, response: Response<Body>) -> BoxFut {
+        if !is_html(response.headers())
+            || response.headers().contains_key(CONTENT_ENCODING)
+            || response.status() == StatusCode::PARTIAL_CONTENT
+        {
+            return Box::new(future::ok(response));
+        }
+        let (mut parts, body) = response.into_parts();
+        Box::new(body.concat2().map(move |body| {
+            let end = body
+                .to_ascii_lowercase()
+                .windows(7)
+                .rposition(|w| w == b"</body>");
+            let end = match end {
+                Some(end) if !self.is_present(&body) => end,
+                _ => return Response::from_parts(parts, Body::from(body)),
+            };
+            let mut page = Vec::with_capacity(body.len() + self.tag.len());
+            page.extend_from_slice(&body[..end]);
+            page.extend_from_slice(self.tag.as_bytes());
+            page.extend_from_slice(&body[end..]);
+            parts.headers.insert(CONTENT_LENGTH, page.len().into());
+            Response::from_parts(parts, Body::from(page))
+        }))
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::future::{self, Future};
+use futures::Stream;
+use hyper::header::{HeaderName, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, HeaderMap, Request, Response};
+use serde_json::{Map, Value};
+
+use crate::body;
+use crate::logging::RequestTag;
+use crate::BoxFut;
+
+/// Where the value written into the request body comes from.
+enum Source {
+    Literal(Value),
+    Header(HeaderName),
+}
+
+/// Sets a field of a JSON request body, e.g. `user.id:$HEADER:X-User-ID`.
+///
+/// The path is a dotted list of object keys, any missing objects along the way are created.
+pub struct JsonRewrite {
+    path: Vec<String>,
+    source: Source,
+}
+
+impl JsonRewrite {
+    /// Parses a `PATH:VALUE` specification from the command line.
+    ///
+    /// The value is taken as JSON if it parses as JSON and as a plain string otherwise, or
+    /// `$HEADER:NAME` copies the value of a request header.
+    pub fn parse(spec: &str) -> Result<JsonRewrite, String> {
+        let mut parts = spec.splitn(2, ':');
+        let path = parts.next().unwrap_or("").trim();
+        let value = match parts.next() {
+            Some(value) if !path.is_empty() => value,
+            _ => return Err(format!("Expected PATH:VALUE but got '{}'", spec)),
+        };
+        let path: Vec<String> = path.split('.').map(|s| s.to_string()).collect();
+        if path.iter().any(|s| s.is_empty()) {
+            return Err(format!("Invalid JSON path in '{}'", spec));
+        }
+        let source = match value.strip_prefix("$HEADER:") {
+            Some(name) => Source::Header(
+                HeaderName::from_bytes(name.trim().as_bytes())
+                    .map_err(|_| format!("Invalid header name in '{}'", spec))?,
+            ),
+            None => Source::Literal(
+                serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string())),
+            ),
+        };
+        Ok(JsonRewrite { path, source })
+    }
+
+    fn value(&self, headers: &HeaderMap) -> Option<Value> {
+        match &self.source {
+            Source::Literal(value) => Some(value.clone()),
+            Source::Header(name) => headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| Value::String(v.to_string())),
+        }
+    }
+
+    fn apply(&self, body: &mut Value, headers: &HeaderMap) {
+        let value = match self.value(headers) {
+            Some(value) => value,
+            None => return,
+        };
+        let (field, parents) = self.path.split_last().unwrap();
+        let mut object = body;
+        for key in parents {
+            let map = match object {
+                Value::Object(map) => map,
+                _ => return,
+            };
+            object = map
+                .entry(key.as_str())
+                .or_insert_with(|| Value::Object(Map::new()));
+        }
+        if let Value::Object(map) = object {
+            map.insert(field.clone(), value);
+        }
+    }
+}
+
+/// Whether the request says that it has a JSON body.
+pub fn is_json<B>(req: &Request<B>) -> bool {
+    is_json_content(req.headers())
+}
+
+fn is_json_content(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .is_some_and(|v| v == "application/json" || v.ends_with("+json"))
+}
+
+/// Buffers the JSON request body and applies the rewrites to it.
+///
+/// A body that does not parse as JSON is forwarded untouched. Resolves to `None` if the body
+/// is more than `limit` bytes.
+pub fn rewrite(
+    req: Request<Body>,
+    rewrites: Arc<Vec<JsonRewrite>>,
+    limit: u64,
+) -> impl Future<Item = Option<Request<Body>>, Error = hyper::Error> {
+    body::buffer_request(req, limit).map(move |buffered| {
+        let (mut parts, body) = buffered?;
+        let mut json: Value = match serde_json::from_slice(&body) {
+            Ok(json) => json,
+            Err(_) => return Some(Request::from_parts(parts, Body::from(body))),
+        };
+        for rewrite in rewrites.iter() {
+            rewrite.apply(&mut json, &parts.headers);
+        }
+        let body = json.to_string();
+        parts.headers.insert(CONTENT_LENGTH, body.len().into());
+        Some(Request::from_parts(parts, Body::from(body)))
+    })
+}
+
+/// A field of the `meta` object in a wrapped JSON response.
+enum MetaValue {
+    RequestId,
+    Timestamp,
+    Literal(Value),
+}
+
+/// Wraps JSON responses in an envelope of `{"data": <response>, "meta": {...}}`.
+#[derive(Default)]
+pub struct JsonEnvelope {
+    meta: Vec<(String, MetaValue)>,
+    /// Whether to wrap arrays as well as objects.
+    pub arrays: bool,
+}
+
+impl JsonEnvelope {
+    /// Adds a `NAME:VALUE` meta field from the command line.
+    ///
+    /// The value `$request_id` is the correlation ID and `$timestamp` the time of the
+    /// response, anything else is taken as JSON if it parses and as a string otherwise.
+    pub fn add_meta_field(&mut self, spec: &str) -> Result<(), String> {
+        let mut parts = spec.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        let value = match parts.next() {
+            Some(value) if !name.is_empty() => value,
+            _ => return Err(format!("Expected NAME:VALUE but got '{}'", spec)),
+        };
+        let value = match value {
+            "$request_id" => MetaValue::RequestId,
+            "$timestamp" => MetaValue::Timestamp,
+            v => MetaValue::Literal(
+                serde_json::from_str(v).unwrap_or_else(|_| Value::String(v.to_string())),
+            ),
+        };
+        self.meta.push((name.to_string(), value));
+        Ok(())
+    }
+
+    fn meta(&self, tag: &RequestTag) -> Value {
+        let mut meta = Map::new();
+        if self.meta.is_empty() {
+            if let Some(id) = tag.id() {
+                meta.insert("request_id".into(), Value::String(id.to_string()));
+            }
+            meta.insert("timestamp".into(), Value::String(Utc::now().to_rfc3339()));
+        }
+        for (name, value) in &self.meta {
+            let value = match value {
+                MetaValue::RequestId => match tag.id() {
+                    Some(id) => Value::String(id.to_string()),
+                    None => Value::Null,
+                },
+                MetaValue::Timestamp => Value::String(Utc::now().to_rfc3339()),
+                MetaValue::Literal(value) => value.clone(),
+            };
+            meta.insert(name.clone(), value);
+        }
+        Value::Object(meta)
+    }
+
+    /// Buffers a JSON response and wraps it in the envelope.
+    ///
+    /// Anything that is not uncompressed JSON, or does not parse, is returned untouched.
+    pub fn wrap(self: Arc<Self>, response: Response<Body>, tag: RequestTag) -> BoxFut {
+        if !is_json_content(response.headers()) || response.headers().contains_key(CONTENT_ENCODING)
+        {
+            return Box::new(future::ok(response));
+        }
+        let (mut parts, body) = response.into_parts();
+        Box::new(body.concat2().map(move |body| {
+            let data: Value = match serde_json::from_slice(&body) {
+                Ok(data @ Value::Object(_)) => data,
+                Ok(data @ Value::Array(_)) if self.arrays => data,
+                _ => return Response::from_parts(parts, Body::from(body)),
+            };
+            let mut envelope = Map::new();
+            envelope.insert("data".into(), data);
+            envelope.insert("meta".into(), self.meta(&tag));
+            let body = Value::Object(envelope).to_string();
+            parts.headers.insert(CONTENT_LENGTH, body.len().into());
+            Response::from_parts(parts, Body::from(body))
+        }))
+    }
+}
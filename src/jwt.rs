@@ -0,0 +1,78 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION};
+use hyper::HeaderMap;
+use serde_json::{Map, Value};
+
+/// Copies a claim from the bearer token into a request header.
+///
+/// The token signature is NOT verified, so this must only be used where the token has
+/// already been validated by something in front of the proxy. Otherwise a client can
+/// simply forge whatever claims it likes.
+pub struct ClaimHeader {
+    claim: String,
+    header: HeaderName,
+}
+
+impl ClaimHeader {
+    /// Parses a `CLAIM:HEADER` specification from the command line.
+    pub fn parse(spec: &str) -> Result<ClaimHeader, String> {
+        let mut parts = spec.splitn(2, ':');
+        let claim = parts.next().unwrap_or("").trim();
+        let header = match parts.next() {
+            Some(header) if !claim.is_empty() => header.trim(),
+            _ => return Err(format!("Expected CLAIM:HEADER but got '{}'", spec)),
+        };
+        let header = HeaderName::from_bytes(header.as_bytes())
+            .map_err(|_| format!("Invalid header name in '{}'", spec))?;
+        Ok(ClaimHeader {
+            claim: claim.to_string(),
+            header,
+        })
+    }
+}
+
+/// Decodes the payload of the bearer token in the `Authorization` header, if there is one.
+fn claims(headers: &HeaderMap) -> Option<Map<String, Value>> {
+    let authorization = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let token = authorization
+        .strip_prefix("Bearer ")
+        .or_else(|| authorization.strip_prefix("bearer "))?;
+    let payload = token.trim().split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    match serde_json::from_slice(&payload).ok()? {
+        Value::Object(claims) => Some(claims),
+        _ => None,
+    }
+}
+
+fn header_value(claim: &Value) -> Option<HeaderValue> {
+    match claim {
+        Value::Null => None,
+        Value::String(s) => HeaderValue::from_str(s).ok(),
+        other => HeaderValue::from_str(&other.to_string()).ok(),
+    }
+}
+
+/// Replaces the claim headers with the claims from the request's bearer token.
+///
+/// Any values the client sent for these headers are removed so they cannot be spoofed.
+pub fn apply(claim_headers: &[ClaimHeader], headers: &mut HeaderMap) {
+    let claims = claims(headers);
+    for claim_header in claim_headers {
+        headers.remove(&claim_header.header);
+        let value = claims
+            .as_ref()
+            .and_then(|c| c.get(&claim_header.claim))
+            .and_then(header_value);
+        if let Some(value) = value {
+            headers.insert(claim_header.header.clone(), value);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::error::Error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use hyper::{Body, Response};
+
+/// Caps the number of requests being proxied at the same time.
+#[derive(Clone)]
+pub struct ConcurrencyLimit {
+    max: usize,
+    current: Arc<AtomicUsize>,
+}
+
+impl ConcurrencyLimit {
+    /// A `max` of zero means unlimited.
+    pub fn new(max: usize) -> ConcurrencyLimit {
+        ConcurrencyLimit {
+            max,
+            current: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Takes a permit, or returns `None` if all permits are in use.
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let acquired = self.current.fetch_add(1, Ordering::SeqCst);
+        if self.max > 0 && acquired >= self.max {
+            self.current.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Permit(self.current.clone()))
+    }
+
+    /// The number of permits currently held.
+    pub fn in_use(&self) -> usize {
+        self.current.load(Ordering::SeqCst)
+    }
+}
+
+/// Holds one of the permits until dropped.
+pub struct Permit(Arc<AtomicUsize>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Marks a response to a request that was turned away for lack of a permit.
+#[derive(Clone, Copy)]
+pub struct Refused;
+
+/// Resets the HTTP/2 stream of a request that was turned away with `REFUSED_STREAM`, which
+/// tells the client that nothing was done and the request can be safely retried.
+pub fn refuse_stream(
+    response: Response<Body>,
+) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+    if response.extensions().get::<Refused>().is_some() {
+        return Err(Box::new(h2::Error::from(h2::Reason::REFUSED_STREAM)));
+    }
+    Ok(response)
+}
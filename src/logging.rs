@@ -0,0 +1,74 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use hyper::Request;
+
+static SILENT: AtomicBool = AtomicBool::new(false);
+
+/// Suppresses all log output. Fatal errors are reported by panicking and are always printed.
+pub fn set_silent(silent: bool) {
+    SILENT.store(silent, Ordering::Relaxed);
+}
+
+pub fn is_silent() -> bool {
+    SILENT.load(Ordering::Relaxed)
+}
+
+/// Like `println!` but suppressed by `--silent`.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if !$crate::logging::is_silent() {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Like `eprintln!` but suppressed by `--silent`.
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        if !$crate::logging::is_silent() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+/// Identifies the request that a log line belongs to, e.g. by its correlation ID.
+///
+/// It is carried in the request extensions so that it follows the request to the proxy.
+#[derive(Clone, Default)]
+pub struct RequestTag(Option<String>);
+
+impl RequestTag {
+    pub fn new(id: String) -> RequestTag {
+        RequestTag(Some(id))
+    }
+
+    pub fn of<B>(req: &Request<B>) -> RequestTag {
+        req.extensions()
+            .get::<RequestTag>()
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The correlation ID, if the request has one.
+    pub fn id(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+impl fmt::Display for RequestTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(id) => write!(f, "[{}] ", id),
+            None => Ok(()),
+        }
+    }
+}
@@ -6,18 +6,77 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use std::collections::HashMap;
 use std::env;
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use chrono::Utc;
 use futures::future::{self, Future};
-use getopts::Options;
-use hyper::header::HeaderValue;
+use getopts::{Matches, Options};
+use hyper::header::{
+    HeaderName, HeaderValue, ACCEPT_ENCODING, AUTHORIZATION, CONNECTION, CONTENT_LENGTH, HOST,
+    RETRY_AFTER,
+};
+use hyper::http::uri::Authority;
 use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, HeaderMap, Request, Response, Server, Uri};
+use hyper::{Body, Client, Request, Response, Server, StatusCode, Uri, Version};
+use tokio_timer::Delay;
+use uuid::Uuid;
+
+#[macro_use]
+mod logging;
+
+mod access_log;
+mod adaptive;
+mod balancer;
+mod body;
+mod breaker;
+mod config;
+mod connector;
+mod cookies;
+mod deadline;
+mod dns;
+mod encoding;
+mod errors;
+mod fake;
+mod forwarded;
+mod glob;
+mod grpc;
+mod grpc_web;
+mod headers;
+mod html;
+mod json_body;
+mod jwt;
+mod limit;
+mod metrics;
+mod proxy;
+mod redirect;
+mod retry;
+mod signing;
+mod sni;
+mod static_files;
+mod tap;
+mod timing;
+mod tls;
+mod vhost;
+mod wasm;
+mod websocket;
 
 type BoxFut = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+type ReqFut = Box<dyn Future<Item = Transformed, Error = hyper::Error> + Send>;
+
+/// A request on its way upstream, or the response when a transform has already answered it.
+enum Transformed {
+    Forward(Request<Body>),
+    Respond(Response<Body>),
+}
 
 fn debug_request(req: Request<Body>) -> BoxFut {
     let body_str = format!("{:?}", req);
@@ -37,32 +96,53 @@ fn redirect(url: &str) -> BoxFut {
     Box::new(future::ok(response))
 }
 
-fn not_found() -> BoxFut {
-    let response = Response::builder()
-        .status(404)
-        .body(Body::from(
-            "
-            <!DOCTYPE html>
-            <html>
-            <head>
-            <title>Not Found</title>
-            <style>
-                body {
-                    width: 35em;
-                    margin: 0 auto;
-                    font-family: Tahoma, Verdana, Arial, sans-serif;
-                }
-            </style>
-            </head>
-            <body>
-            <h1>Not Found</h1>
-            <p>Sorry, the page you are looking for cannot be found</p>
-            </body>
-            </html>
-        ",
-        ))
-        .unwrap();
-    Box::new(future::ok(response))
+fn not_found(style: &errors::ErrorStyle) -> BoxFut {
+    Box::new(future::ok(style.response(
+        StatusCode::NOT_FOUND,
+        "Sorry, the page you are looking for cannot be found",
+        errors::ErrorFormat::Html,
+    )))
+}
+
+fn payload_too_large(style: &errors::ErrorStyle) -> Response<Body> {
+    style.response(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "Request body too large",
+        errors::ErrorFormat::Text,
+    )
+}
+
+fn bad_request(style: &errors::ErrorStyle, message: String) -> Response<Body> {
+    style.response(
+        StatusCode::BAD_REQUEST,
+        message.as_str(),
+        errors::ErrorFormat::Text,
+    )
+}
+
+fn service_unavailable(style: &errors::ErrorStyle) -> Response<Body> {
+    let mut response = style.response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Too many concurrent requests",
+        errors::ErrorFormat::Text,
+    );
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+    response.extensions_mut().insert(limit::Refused);
+    response
+}
+
+fn upstream_shedding(style: &errors::ErrorStyle) -> Response<Body> {
+    let mut response = style.response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Too many upstream errors",
+        errors::ErrorFormat::Text,
+    );
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from_static("1"));
+    response
 }
 
 fn create_options() -> Options {
@@ -75,7 +155,12 @@ fn create_options() -> Options {
         "the port to listen for requests on (default: 8080)",
         "PORT",
     );
-    opts.optopt("t", "target-url", "the target base URL to proxy", "URL");
+    opts.optmulti(
+        "t",
+        "target-url",
+        "the target base URL to proxy (repeat to balance requests across several targets)",
+        "URL",
+    );
     opts.optopt(
         "s",
         "source-path",
@@ -85,9 +170,740 @@ fn create_options() -> Options {
     opts.optopt(
         "c",
         "cache-control",
-        "the cache control header to inject if none is provided",
+        "the cache control header to inject if none is provided (same as --add-response-header-if-missing Cache-Control:VALUE)",
         "VALUE",
     );
+    opts.optmulti(
+        "",
+        "add-response-header",
+        "a header to set on all proxied responses, replacing any value from the target",
+        "NAME:VALUE",
+    );
+    opts.optmulti(
+        "",
+        "add-response-header-if-missing",
+        "a header to set on proxied responses where the target did not provide it",
+        "NAME:VALUE",
+    );
+    opts.optopt(
+        "",
+        "request-body-max-bytes",
+        "the maximum size of a request body to forward to the target (default: unlimited)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "request-buffer-max-bytes",
+        "the largest request body to keep in memory so that the request can be failed over, hedged, retried or redirected, or its body rewritten, transformed or compressed; larger requests are sent once as they are, or get a 413 when their body has to change (default: 10485760)",
+        "BYTES",
+    );
+    opts.optmulti(
+        "",
+        "static-dir",
+        "serve files from DIR for GET and HEAD requests under PREFIX, falling back to the target for other methods or when there is no such file in DIR, which includes symlinks to outside of it",
+        "PREFIX:DIR",
+    );
+    opts.optflag("", "silent", "suppress all log output except fatal errors");
+    opts.optflag(
+        "",
+        "preserve-host",
+        "forward the Host header of the original request to the target",
+    );
+    opts.optflag(
+        "",
+        "rewrite-host",
+        "replace the Host header with the host of the target URL",
+    );
+    opts.optopt(
+        "",
+        "upstream-basic-auth",
+        "authenticate to the target with these Basic auth credentials, replacing any Authorization header from the client",
+        "USER:PASSWORD",
+    );
+    opts.optopt(
+        "",
+        "upstream-basic-auth-file",
+        "like --upstream-basic-auth but reading the USER:PASSWORD credentials from the first line of this file",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "upstream-http-authority",
+        "replace the Host header of every forwarded request with this value, whatever the target URL (HTTP/2 upstreams still get the target authority in :authority)",
+        "HOST[:PORT]",
+    );
+    opts.optopt(
+        "",
+        "upstream-ca-cert",
+        "additional PEM CA certificates to trust for HTTPS targets",
+        "PEM_FILE",
+    );
+    opts.optopt(
+        "",
+        "upstream-client-cert",
+        "the PEM certificate chain to present to HTTPS targets",
+        "PEM_FILE",
+    );
+    opts.optopt(
+        "",
+        "upstream-client-key",
+        "the PEM private key for --upstream-client-cert",
+        "PEM_FILE",
+    );
+    opts.optopt(
+        "",
+        "upstream-client-cert-reload-interval",
+        "how often to reload the TLS certificates and key from disk (default: never)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "upstream-dns-cache-ttl",
+        "how long to cache the resolved addresses of the target host, 0 to disable (default: 30)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "upstream-local-addr",
+        "the local IP address to make connections to the target from",
+        "IP",
+    );
+    opts.optopt(
+        "",
+        "keepalive-requests",
+        "the number of requests to serve on a keep-alive connection before closing it, 0 for unlimited (default: 0)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "upstream-balancer",
+        "how to choose between multiple target URLs: round-robin, least-connections, random, ip-hash (by the client IP after --trusted-proxy-count) or power-of-two-choices (the less busy of two picked at random) (default: round-robin)",
+        "STRATEGY",
+    );
+    opts.optopt(
+        "",
+        "log-correlation-id-header",
+        "a request header whose value is included in every log line for the request, generated if absent",
+        "NAME",
+    );
+    opts.optmulti(
+        "",
+        "upstream-failover-url",
+        "a target URL to try, in the order given, when the previous target fails with a 5xx or connection error",
+        "URL",
+    );
+    opts.optflag(
+        "",
+        "decompress-brotli",
+        "decompress brotli encoded responses for clients that do not accept brotli, replacing those that are larger than --upstream-max-response-size (default 64MiB) either compressed or decompressed with a 502",
+    );
+    opts.optmulti(
+        "",
+        "extract-jwt-claim-unsecured",
+        "copy a claim from the bearer token into a request header WITHOUT verifying the token (only safe if already validated in front of the proxy)",
+        "CLAIM:HEADER",
+    );
+    opts.optopt(
+        "",
+        "max-concurrent-requests",
+        "the maximum number of requests to proxy at once, further requests get a 503 (default: 0 = unlimited)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "metrics-path",
+        "serve Prometheus metrics at this path",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "metrics-push-gateway",
+        "periodically push Prometheus metrics to this Pushgateway URL",
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "metrics-push-interval",
+        "the seconds between pushes to the Pushgateway (default: 15)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "metrics-job-name",
+        "the job name to push metrics under (default: sidecar-http-proxy)",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "upstream-tls-verify-hostname",
+        "set to false to check that upstream certificates are from a trusted CA without checking the host name (default: true)",
+        "true|false",
+    );
+    opts.optopt(
+        "",
+        "tap-socket",
+        "stream a copy of each proxied request and response as length-prefixed JSON to clients of this Unix socket",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "tap-sample-rate",
+        "the percentage of requests to tap (default: 100)",
+        "0-100",
+    );
+    opts.optflag(
+        "",
+        "tap-unredacted",
+        "include credentials and cookies in tapped headers",
+    );
+    opts.optopt(
+        "",
+        "upstream-retry-budget",
+        "the percentage of requests that may be failed over, hedged or retried after a connect error each second, on top of 10 retries that are always allowed (default: 20)",
+        "PERCENT",
+    );
+    opts.optopt(
+        "",
+        "trusted-proxy-count",
+        "the number of proxies in front of this one whose X-Forwarded-For entries can be trusted to find the client IP (default: 0 = use the connection's peer address)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "upstream-response-header-timeout",
+        "the milliseconds to wait for the upstream to start responding before returning a 504",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "upstream-max-response-size",
+        "the maximum size of an upstream response body in bytes, larger responses are replaced with a 502",
+        "BYTES",
+    );
+    opts.optmulti(
+        "",
+        "rewrite-request-body-json",
+        "set a field of JSON request bodies, e.g. user.id:42 or user.id:$HEADER:X-User-ID",
+        "PATH:VALUE",
+    );
+    opts.optflag(
+        "",
+        "request-id-propagate",
+        "pass on the correlation ID sent by the client, only generating one when it is absent (the default)",
+    );
+    opts.optflag(
+        "",
+        "request-id-always-generate",
+        "always generate a new correlation ID, forwarding any ID sent by the client as X-Original-Request-ID",
+    );
+    opts.optflag(
+        "",
+        "sni-passthrough-mode",
+        "forward TLS connections without decrypting them, to the https target URL whose host matches the SNI server name (no HTTP features apply)",
+    );
+    opts.optflag(
+        "",
+        "upstream-follow-redirects",
+        "follow 301, 302, 303, 307 and 308 redirects from the upstream instead of returning them",
+    );
+    opts.optopt(
+        "",
+        "upstream-max-redirects",
+        "the most redirects to follow before returning a 502 (default: 5)",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "upstream-follow-cross-origin-redirects",
+        "also follow redirects to a different scheme, host or port",
+    );
+    opts.optopt(
+        "",
+        "redirect-method-change-policy",
+        "how to follow a 301 or 302 redirect of a request other than GET or HEAD: rfc re-sends it unchanged, strict returns the redirect to the client and permissive re-sends it as a GET without a body, while a 303 always becomes a GET (default: rfc)",
+        "rfc|strict|permissive",
+    );
+    opts.optopt(
+        "",
+        "upstream-tls-cipher-suites",
+        "a comma separated list of the only TLS cipher suites to use for upstream connections, e.g. TLS_AES_256_GCM_SHA384",
+        "LIST",
+    );
+    opts.optflag(
+        "",
+        "wrap-json-response",
+        "wrap JSON object responses as {\"data\": <response>, \"meta\": {...}}",
+    );
+    opts.optflag(
+        "",
+        "wrap-json-array-response",
+        "wrap JSON array responses too (implies --wrap-json-response)",
+    );
+    opts.optmulti(
+        "",
+        "json-wrap-meta-field",
+        "a field of the meta object, where $request_id and $timestamp are filled in per request (default: request_id and timestamp)",
+        "NAME:VALUE",
+    );
+    opts.optflag(
+        "",
+        "upstream-send-trailers",
+        "talk HTTP/2 to the upstream so that request trailers (e.g. gRPC) are forwarded, the upstream must support HTTP/2",
+    );
+    opts.optopt(
+        "",
+        "request-signing-secret",
+        "sign upstream requests with an HMAC-SHA256 of the timestamp, method, path and body using this secret",
+        "SECRET",
+    );
+    opts.optopt(
+        "",
+        "request-signing-header",
+        "the header to put the request signature in (default: X-Proxy-Signature)",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "request-signing-max-body-bytes",
+        "the largest request body that will be buffered for signing, larger bodies get a 413 (default: 1048576)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "upstream-idle-timeout",
+        "close pooled upstream connections after they have been idle this long, set it a little below the upstream's own idle timeout (default: 90)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "upstream-proxy-protocol",
+        "start each upstream connection with a PROXY protocol header for the client, either v1 or v2, this stops upstream connections from being reused",
+        "VERSION",
+    );
+    opts.optmulti(
+        "",
+        "sni-rewrite",
+        "send requests for host names matching the regex to the target URL instead, by TLS SNI in SNI passthrough mode and by the Host header otherwise (repeatable, the longest match wins)",
+        "HOSTNAME_REGEX:TARGET_URL",
+    );
+    opts.optopt(
+        "",
+        "access-log-format",
+        "log an access log line for every request in this Apache style format, with %h %t %r %s %b %D %I %{HEADER}i and %{HEADER}o",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "upstream-tls-min-version",
+        "the lowest TLS version to use for upstream connections, 1.2 or 1.3 (default: 1.2)",
+        "VERSION",
+    );
+    opts.optopt(
+        "",
+        "upstream-tls-max-version",
+        "the highest TLS version to use for upstream connections, 1.2 or 1.3 (default: 1.3)",
+        "VERSION",
+    );
+    opts.optopt(
+        "",
+        "upstream-pool-max-idle-per-host",
+        "the most idle connections to keep open to each upstream host (default: unlimited)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "upstream-pool-max-connection-lifetime",
+        "stop reusing upstream connections once they are this old, e.g. when the upstream rotates its instances (default: unlimited)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "fake-upstream-status",
+        "do not call the upstream but return a response with this status code, for testing (default: 200 when any --fake-upstream option is used)",
+        "CODE",
+    );
+    opts.optopt(
+        "",
+        "fake-upstream-body",
+        "the body of the fake upstream response",
+        "STRING",
+    );
+    opts.optmulti(
+        "",
+        "fake-upstream-headers",
+        "a header to add to the fake upstream response (repeatable)",
+        "NAME:VALUE",
+    );
+    opts.optopt(
+        "",
+        "fake-upstream-delay",
+        "how long the fake upstream waits before responding",
+        "MS",
+    );
+    opts.optflag(
+        "",
+        "upstream-error-body-passthrough",
+        "return 4xx and 5xx responses from the upstream exactly as sent, without decoding, wrapping or size limits",
+    );
+    opts.optflag(
+        "",
+        "upstream-grpc-timeout",
+        "use the grpc-timeout header of gRPC requests as the upstream response header timeout, answering with a gRPC DEADLINE_EXCEEDED status when it runs out",
+    );
+    opts.optflag(
+        "",
+        "strip-query-string",
+        "remove the query string from requests before forwarding them (it is still logged)",
+    );
+    opts.optmulti(
+        "",
+        "strip-query-string-path",
+        "remove the query string only from requests whose path matches the glob, where * stays within a path segment and ** does not (repeatable)",
+        "GLOB",
+    );
+    opts.optmulti(
+        "",
+        "upstream-timeout-path",
+        "the milliseconds to wait for the upstream to start responding to requests whose path matches the glob, in place of --upstream-response-header-timeout and --upstream-adaptive-timeout, where the first matching glob wins (repeatable)",
+        "GLOB=MS",
+    );
+    opts.optopt(
+        "",
+        "config",
+        "read more options from this file, one per line as NAME = VALUE, reloading the response headers, --sni-rewrite, --strip-query-string* and --upstream-timeout-path options on SIGHUP",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "retry-count",
+        "how many times to retry a request that failed in a way that is safe to retry (default: 0)",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "upstream-retry-on-connect-error",
+        "retry requests that could not connect to the upstream, on the next target not tried yet, up to --retry-count times (within --upstream-retry-budget)",
+    );
+    opts.optopt(
+        "",
+        "request-body-transform-wasm",
+        "replace request bodies with the output of the transform_request function of this WebAssembly module, run without any imports (needs the wasm feature)",
+        "FILE.wasm",
+    );
+    opts.optopt(
+        "",
+        "wasm-fuel-limit",
+        "the most fuel --request-body-transform-wasm may use for each request before it is stopped and the client gets a 500 (default: unlimited)",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "upstream-compress-request",
+        "gzip JSON, text and form request bodies for upstreams that have sent Accept-Encoding: gzip in a response",
+    );
+    opts.optopt(
+        "",
+        "upstream-compress-request-min-bytes",
+        "the smallest request body to compress with --upstream-compress-request (default: 1024)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "upstream-tcp-keepalive-idle",
+        "turn on TCP keepalive for upstream connections, sending the first probe after they are idle for this long (default: the OS setting)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "upstream-tcp-keepalive-interval",
+        "turn on TCP keepalive for upstream connections, with this long between probes (default: the OS setting)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "upstream-tcp-keepalive-count",
+        "turn on TCP keepalive for upstream connections, dropping them after this many unanswered probes (default: the OS setting)",
+        "N",
+    );
+    opts.optmulti(
+        "",
+        "upstream-interface",
+        "spread new upstream connections round robin across the addresses of these network interfaces, preferring IPv4 (repeatable)",
+        "IFNAME",
+    );
+    opts.optopt(
+        "",
+        "upstream-max-header-size",
+        "the most bytes of HTTP/1 response headers to buffer from the target, larger ones get a 502 (min: 8192, default: 417792)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "client-max-header-size",
+        "the most bytes of HTTP/1 request headers to buffer from clients, larger ones are rejected (min: 8192, default: 417792)",
+        "BYTES",
+    );
+    opts.optmulti(
+        "",
+        "prometheus-labels",
+        "constant labels to add to every exported metric, e.g. env=prod,region=us-east-1 (repeatable)",
+        "KEY=VALUE[,KEY=VALUE...]",
+    );
+    opts.optflag(
+        "",
+        "upstream-prometheus-labels",
+        "count proxied responses in proxy_requests_total by a backend label, the target URL unless given an alias with --backend-label",
+    );
+    opts.optmulti(
+        "",
+        "backend-label",
+        "the backend label for a target URL in proxy_requests_total, implies --upstream-prometheus-labels (repeatable)",
+        "URL:LABEL",
+    );
+    opts.optopt(
+        "",
+        "prometheus-namespace",
+        "prefix every exported metric name with NS_, e.g. NS_proxy_requests_total",
+        "NS",
+    );
+    opts.optopt(
+        "",
+        "upstream-h2-initial-stream-window",
+        "the HTTP/2 flow control window for each stream to the target (default: 65535)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "upstream-h2-initial-connection-window",
+        "the HTTP/2 flow control window for each connection to the target (default: 65535)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "client-h2-initial-stream-window",
+        "the HTTP/2 flow control window for each stream from clients (default: 65535)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "client-h2-initial-connection-window",
+        "the HTTP/2 flow control window for each connection from clients (default: 65535)",
+        "BYTES",
+    );
+    opts.optflag(
+        "",
+        "upstream-adaptive-timeout",
+        "set the upstream response header timeout from the P95 latency of the upstream, recomputed every --adaptive-timeout-window",
+    );
+    opts.optopt(
+        "",
+        "adaptive-timeout-multiplier",
+        "the multiple of the P95 latency to use as the adaptive timeout (default: 2.0)",
+        "FACTOR",
+    );
+    opts.optopt(
+        "",
+        "adaptive-timeout-window",
+        "the seconds of latencies to compute each adaptive timeout from (default: 60)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "adaptive-timeout-min",
+        "the shortest adaptive timeout in milliseconds (default: 100)",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "adaptive-timeout-max",
+        "the longest adaptive timeout in milliseconds, also used until the first window ends (default: 30000)",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "upstream-grpc-max-timeout",
+        "lower the grpc-timeout header of gRPC calls to at most this many milliseconds, adding it to calls without one",
+        "MS",
+    );
+    opts.optmulti(
+        "",
+        "access-log-exclude-path",
+        "do not write --access-log-format lines for requests whose path matches this glob, in addition to the --metrics-path (repeatable)",
+        "GLOB",
+    );
+    opts.optmulti(
+        "",
+        "upstream-ws-subprotocol",
+        "only allow WebSocket upgrades that offer these subprotocols, rejecting others with a 400 (repeatable, default: any)",
+        "PROTOCOL",
+    );
+    opts.optmulti(
+        "",
+        "upstream-dns-servers",
+        "resolve the target host with these DNS servers instead of the system resolver, as IP:PORT for UDP or tcp://IP:PORT (repeatable)",
+        "IP:PORT",
+    );
+    opts.optopt(
+        "",
+        "response-header-max-age-cap",
+        "lower the max-age and s-maxage of upstream Cache-Control headers, and any later Expires, to at most this many seconds",
+        "SECONDS",
+    );
+    opts.optmulti(
+        "",
+        "rewrite-cookie-domain",
+        "replace Domain=FROM_DOMAIN with Domain=TO_DOMAIN in upstream Set-Cookie headers (repeatable)",
+        "FROM_DOMAIN:TO_DOMAIN",
+    );
+    opts.optflag(
+        "",
+        "add-cookie-secure-flag",
+        "add the Secure attribute to upstream Set-Cookie headers, for when clients reach the proxy over HTTPS",
+    );
+    opts.optflag(
+        "",
+        "upstream-http1-only",
+        "never talk HTTP/2 to the upstream, for services that misbehave when it is attempted",
+    );
+    opts.optopt(
+        "",
+        "upstream-tcp-user-timeout",
+        "drop upstream connections whose sent data goes unacknowledged for this many milliseconds (TCP_USER_TIMEOUT, Linux only)",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "websocket-max-frame-size",
+        "close WebSocket connections with a 1009 status when either side sends a frame with a payload over this many bytes",
+        "BYTES",
+    );
+    opts.optflag(
+        "",
+        "upstream-grpc-reflection-proxy",
+        "stream gRPC calls straight through without buffering their request bodies, so that bidirectional streaming calls such as server reflection work, at the cost of skipping failover, redirects, connect retries and request body transforms for them (needs --upstream-send-trailers)",
+    );
+    opts.optopt(
+        "",
+        "upstream-retry-backoff",
+        "how long to wait between --upstream-retry-on-connect-error retries: fixed:MS, exponential:BASE_MS:MAX_MS or exponential+jitter:BASE_MS:MAX_MS (default: exponential:100:5000)",
+        "STRATEGY",
+    );
+    opts.optopt(
+        "",
+        "upstream-error-threshold-warn",
+        "the percentage of upstream 5xx responses in a window above which a growing share of requests get a 503 instead of being forwarded (needs --upstream-error-threshold-open)",
+        "PERCENT",
+    );
+    opts.optopt(
+        "",
+        "upstream-error-threshold-open",
+        "the percentage of upstream 5xx responses in a window at which every request gets a 503, halving the share turned away after each window with fewer than 10 responses",
+        "PERCENT",
+    );
+    opts.optopt(
+        "",
+        "upstream-error-threshold-window",
+        "the seconds over which to measure the upstream error rate (default: 10)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "response-inject-script-tag",
+        "add <script src=\"SCRIPT_URL\" defer></script> before the </body> of uncompressed text/html responses that do not already load it",
+        "SCRIPT_URL",
+    );
+    opts.optflag(
+        "",
+        "upstream-no-send-request-id",
+        "log with the correlation ID but do not forward it (or X-Original-Request-ID) to the upstream, for upstreams that generate their own",
+    );
+    opts.optopt(
+        "",
+        "client-max-concurrent-streams",
+        "the most requests an HTTP/2 client may have in flight on one connection, streams refused by --max-concurrent-requests are reset with REFUSED_STREAM rather than answered with a 503 (default: 100)",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "log-request-timing",
+        "log how long each proxied request took to connect upstream, to the first and last response byte, and in total",
+    );
+    opts.optflag(
+        "",
+        "add-timing-headers",
+        "add X-Proxy-Connect-Time and X-Proxy-TTFB headers, in milliseconds, to proxied responses",
+    );
+    opts.optflag(
+        "",
+        "upstream-grpc-web",
+        "translate gRPC-Web calls from browsers (application/grpc-web and application/grpc-web-text) to gRPC for the upstream and the responses back, the upstream is talked to over HTTP/2 as with --upstream-send-trailers",
+    );
+    opts.optflag(
+        "",
+        "upstream-grpc-status-passthrough",
+        "never rebuild the body of gRPC responses, e.g. for --upstream-max-response-size, so that their grpc-status, grpc-message and grpc-status-details-bin trailers reach the client as the upstream sent them",
+    );
+    opts.optopt(
+        "",
+        "error-response-format",
+        "the body of the proxy's own error responses: json, html or text (default: json for clients that accept application/json, otherwise html for 404 and text for the rest)",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "upstream-hedge-after",
+        "send a copy of the request to another target from --upstream-balancer that does not have one yet if the upstream has not sent the response headers within this many milliseconds, and use whichever response comes first (within --upstream-retry-budget)",
+        "MS",
+    );
+    opts.optopt(
+        "",
+        "upstream-max-parallel-retries",
+        "the most copies of a request to send with --upstream-hedge-after, one more each time it passes without a response (default: 1)",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "upstream-deadline-propagation",
+        "pass the time left until the RFC 3339 deadline in --deadline-header on to the upstream, as the grpc-timeout of gRPC calls and in X-Proxy-Deadline-Remaining-Ms otherwise, answering with a 504 once it has passed",
+    );
+    opts.optopt(
+        "",
+        "deadline-header",
+        "the request header with the deadline for --upstream-deadline-propagation (default: X-Request-Deadline)",
+        "NAME",
+    );
+    opts.optopt(
+        "",
+        "add-request-header-timestamp",
+        "set this header on requests to the upstream to the time they are sent, before it is signed with --request-signing-secret",
+        "HEADER",
+    );
+    opts.optopt(
+        "",
+        "request-timestamp-format",
+        "the strftime format of --add-request-header-timestamp, e.g. %s for Unix seconds (default: RFC 3339 in UTC with milliseconds)",
+        "FORMAT",
+    );
+    opts.optflag(
+        "",
+        "client-tcp-nodelay",
+        "disable Nagle's algorithm on client connections, so that each chunk of a streamed response (event streams and others without a Content-Length) is sent on as soon as it arrives rather than waiting for more data",
+    );
+    opts.optflag(
+        "",
+        "upstream-retry-safe-methods-only",
+        "only send GET, HEAD and OPTIONS requests upstream more than once, by --upstream-retry-on-connect-error, --upstream-failover-url or --upstream-hedge-after (the default)",
+    );
+    opts.optflag(
+        "",
+        "upstream-retry-idempotent-methods",
+        "also retry, fail over and hedge PUT and DELETE requests",
+    );
+    opts.optflag(
+        "",
+        "upstream-retry-all-methods",
+        "retry, fail over and hedge requests whatever their method, even POST and PATCH which may then take effect twice",
+    );
     opts
 }
 
@@ -98,125 +914,2072 @@ fn print_usage(program: &str, opts: Options) {
     println!("Proxies requests to a remote service (with optional path prefix stripping)");
 }
 
-fn main() {
-    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-    let args: Vec<String> = env::args().collect();
-    let program = args[0].clone();
+/// Everything needed to handle a request, shared by all connections.
+struct Settings {
+    balancer: balancer::Balancer,
+    /// The settings that are replaced when the configuration file is reloaded.
+    config: Arc<RwLock<Arc<config::Config>>>,
+    failover: Vec<balancer::Backend>,
+    /// How many times to retry a request that could not connect to the upstream.
+    connect_retries: u32,
+    hedge_after: Option<Duration>,
+    retry_methods: retry::RetryMethods,
+    /// The header with the absolute deadline of a request, when deadlines are propagated.
+    deadline_header: Option<HeaderName>,
+    /// The most copies of a request to send while waiting for the first response.
+    hedge_max: u32,
+    decompress_brotli: bool,
+    jwt_claim_headers: Vec<jwt::ClaimHeader>,
+    concurrency: limit::ConcurrencyLimit,
+    metrics: Arc<metrics::Metrics>,
+    /// The `backend` label for each backend URL, when proxied responses are counted by backend.
+    backend_labels: Option<HashMap<String, String>>,
+    metrics_path: Option<String>,
+    access_log: Option<access_log::AccessLogFormat>,
+    access_log_exclude: Vec<glob::Glob>,
+    websocket_subprotocols: Vec<String>,
+    websocket_max_frame_size: Option<u64>,
+    tap: Option<tap::Tap>,
+    retry_budget: Arc<retry::RetryBudget>,
+    retry_backoff: retry::BackoffStrategy,
+    trusted_proxy_count: usize,
+    upstream_header_timeout: Option<Duration>,
+    adaptive_timeout: Option<Arc<adaptive::AdaptiveTimeout>>,
+    error_rate_breaker: Option<Arc<breaker::ErrorRateBreaker>>,
+    upstream_grpc_timeout: bool,
+    /// Whether gRPC calls skip everything that buffers the request body.
+    upstream_grpc_streaming: bool,
+    upstream_grpc_max_timeout: Option<Duration>,
+    upstream_grpc_web: bool,
+    upstream_max_response_size: Option<u64>,
+    upstream_error_body_passthrough: bool,
+    /// Leave gRPC response bodies alone so that their status trailers reach the client.
+    upstream_grpc_status_passthrough: bool,
+    max_age_cap: Option<u64>,
+    cookie_rewrite: Option<Arc<cookies::CookieRewrite>>,
+    redirects: Option<redirect::RedirectPolicy>,
+    json_rewrites: Arc<Vec<json_body::JsonRewrite>>,
+    json_envelope: Option<Arc<json_body::JsonEnvelope>>,
+    script_tag: Option<Arc<html::ScriptTag>>,
+    request_signer: Option<Arc<signing::RequestSigner>>,
+    request_transform: Option<Arc<wasm::RequestTransform>>,
+    request_compression: Option<Arc<encoding::RequestCompression>>,
+    fake_upstream: Option<fake::FakeUpstream>,
+    proxy_protocol_clients: Option<proxy::ProxyProtocolClients>,
+    source_match: String,
+    source_prefix: String,
+    preserve_host: bool,
+    rewrite_host: bool,
+    upstream_authority: Option<HeaderValue>,
+    upstream_authorization: Option<HeaderValue>,
+    request_timestamp: Option<headers::RequestTimestamp>,
+    request_body_max_bytes: Option<u64>,
+    /// The largest request body to keep in memory to retry or transform the request.
+    request_buffer_max_bytes: u64,
+    static_dirs: Vec<static_files::StaticDir>,
+    /// Swapped for a new client, with an empty pool, when connections reach their lifetime.
+    client: Arc<RwLock<proxy::HttpClient>>,
+    correlation_id_header: Option<HeaderName>,
+    /// Replace the client's correlation ID with one of our own rather than passing it on.
+    request_id_always_generate: bool,
+    upstream_send_request_id: bool,
+    /// Overrides the format of our own error responses, which is otherwise chosen per error.
+    error_response_format: Option<errors::ErrorFormat>,
+    request_timing: Option<Arc<timing::RequestTiming>>,
+}
 
-    let opts = create_options();
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(f) => panic!(f.to_string()),
-    };
-    if matches.opt_present("h") {
-        print_usage(&program, opts);
-        return;
+fn handle(settings: &Arc<Settings>, remote_addr: SocketAddr, mut req: Request<Body>) -> BoxFut {
+    let client_ip = forwarded::ClientIp::resolve(
+        remote_addr.ip(),
+        req.headers(),
+        settings.trusted_proxy_count,
+    );
+    req.extensions_mut().insert(client_ip);
+    if let Some(name) = &settings.correlation_id_header {
+        let client_id = req.headers().get(name).cloned();
+        let id = match client_id.as_ref().and_then(|v| v.to_str().ok()) {
+            Some(id) if !settings.request_id_always_generate => id.to_string(),
+            _ => {
+                if settings.request_id_always_generate {
+                    // only we get to say what the client's ID was
+                    req.headers_mut().remove("X-Original-Request-ID");
+                    if let Some(client_id) = client_id.clone() {
+                        req.headers_mut().insert("X-Original-Request-ID", client_id);
+                    }
+                }
+                let id = Uuid::new_v4().to_string();
+                // forward the generated ID so the target logs the same one
+                req.headers_mut()
+                    .insert(name.clone(), HeaderValue::from_str(id.as_str()).unwrap());
+                id
+            }
+        };
+        if !settings.upstream_send_request_id {
+            req.headers_mut().remove(name);
+            req.headers_mut().remove("X-Original-Request-ID");
+        }
+        req.extensions_mut().insert(logging::RequestTag::new(id));
     }
-    if matches.opt_present("V") {
-        println!("{}", VERSION);
-        return;
+    let style = errors::ErrorStyle::new(settings.error_response_format, &req);
+    req.extensions_mut().insert(style);
+    if settings.metrics_path.as_deref() == Some(req.uri().path()) {
+        return Box::new(future::ok(settings.metrics.response()));
     }
-
-    let port = match matches.opt_str("p") {
-        Some(v) => match v.parse::<u16>() {
-            Ok(v) => v,
-            Err(_) => panic!("Port is supposed to be an integer in the range 0-65535"),
-        },
-        None => 8080,
+    let file = if static_files::serves(req.method()) {
+        settings
+            .static_dirs
+            .iter()
+            .find_map(|dir| Some((dir, dir.resolve(req.uri().path())?)))
+    } else {
+        None
+    };
+    match file {
+        Some((dir, file)) => {
+            let settings = settings.clone();
+            Box::new(
+                dir.serve(file, &req)
+                    .and_then(move |response| match response {
+                        Some(response) => {
+                            log!(
+                                "[{}] {}{} Static HTTP/{}",
+                                Utc::now(),
+                                logging::RequestTag::of(&req),
+                                req.uri(),
+                                response.status().as_u16()
+                            );
+                            Box::new(future::ok(response))
+                        }
+                        None => route(&settings, remote_addr, req),
+                    }),
+            )
+        }
+        None => route(settings, remote_addr, req),
+    }
+}
+
+/// Falls back to the next failover target while the previous attempt fails with a 5xx.
+///
+/// Connection errors are reported by the proxy as a 5xx too, so they also fail over.
+fn failover(
+    settings: Arc<Settings>,
+    remote_addr: SocketAddr,
+    req: Arc<proxy::ReplayableRequest>,
+    attempt: BoxFut,
+    next: usize,
+) -> BoxFut {
+    Box::new(attempt.and_then(move |response| {
+        let backend = match settings.failover.get(next) {
+            Some(backend) if response.status().is_server_error() => backend,
+            _ => return Box::new(future::ok(response)) as BoxFut,
+        };
+        let mut request = req.to_request();
+        if !settings.retry_methods.allows(request.method()) {
+            log!(
+                "[{}] {}Not failing over a {} request after HTTP/{}",
+                Utc::now(),
+                logging::RequestTag::of(&request),
+                request.method(),
+                response.status().as_u16()
+            );
+            return Box::new(future::ok(response));
+        }
+        if !settings.retry_budget.try_retry() {
+            log!(
+                "[{}] {}Retry budget exhausted, not failing over after HTTP/{}",
+                Utc::now(),
+                logging::RequestTag::of(&request),
+                response.status().as_u16()
+            );
+            return Box::new(future::ok(response));
+        }
+        log!(
+            "[{}] {}Failover {}{} after HTTP/{}",
+            Utc::now(),
+            logging::RequestTag::of(&request),
+            backend.url,
+            request.uri(),
+            response.status().as_u16()
+        );
+        if settings.rewrite_host {
+            request.headers_mut().insert(HOST, backend.host.clone());
+        }
+        let in_flight = backend.start();
+        let attempt = counted(
+            in_flight,
+            call_upstream(&settings, remote_addr, &req, backend.url.as_str(), request),
+        );
+        failover(
+            settings.clone(),
+            remote_addr,
+            req.clone(),
+            attempt,
+            next + 1,
+        )
+    }))
+}
+
+/// Retries a request that could not connect to its backend, on the next backend from the
+/// balancer that is not in `tried` yet, backing off between attempts. Once every backend has
+/// been tried it goes back to picking from all of them.
+fn retry_connect(
+    settings: Arc<Settings>,
+    remote_addr: SocketAddr,
+    req: Arc<proxy::ReplayableRequest>,
+    tried: Arc<Mutex<Vec<String>>>,
+    attempt: BoxFut,
+    retry: u32,
+) -> BoxFut {
+    Box::new(attempt.and_then(move |response| {
+        if retry >= settings.connect_retries
+            || response
+                .extensions()
+                .get::<proxy::ConnectFailed>()
+                .is_none()
+            || !settings.retry_methods.allows(req.method())
+        {
+            return Box::new(future::ok(response)) as BoxFut;
+        }
+        let mut request = req.to_request();
+        let client = forwarded::ClientIp::of(&request).unwrap_or_else(|| remote_addr.ip());
+        let backend = {
+            let mut tried = tried.lock().unwrap();
+            let backend = settings
+                .balancer
+                .select_untried(client, &tried)
+                .or_else(|| settings.balancer.select(client));
+            match backend {
+                Some(backend) if settings.retry_budget.try_retry() => {
+                    tried.push(backend.url.clone());
+                    backend
+                }
+                _ => return Box::new(future::ok(response)),
+            }
+        };
+        let delay = settings.retry_backoff.next_delay(retry);
+        log!(
+            "[{}] {}Retry {}{} in {}ms after a connect error",
+            Utc::now(),
+            logging::RequestTag::of(&request),
+            backend.url,
+            request.uri(),
+            delay.as_millis()
+        );
+        if settings.rewrite_host {
+            request.headers_mut().insert(HOST, backend.host.clone());
+        }
+        let url = backend.url.clone();
+        // counted from now so that the backend is not picked for others while we back off
+        let in_flight = backend.start();
+        Box::new(Delay::new(Instant::now() + delay).then(move |_| {
+            let attempt = counted(
+                in_flight,
+                call_upstream(&settings, remote_addr, &req, url.as_str(), request),
+            );
+            retry_connect(
+                settings.clone(),
+                remote_addr,
+                req.clone(),
+                tried.clone(),
+                attempt,
+                retry + 1,
+            )
+        }))
+    }))
+}
+
+/// Races the first attempt against copies of the request sent to the next backends from the
+/// balancer, one every `--upstream-hedge-after` that it goes without response headers, and
+/// answers with whichever responds first. Dropping the others cancels them.
+///
+/// Each hedge goes to a backend that is not in `tried` yet, and there is no hedge once every
+/// backend has an attempt.
+fn hedge(
+    settings: Arc<Settings>,
+    remote_addr: SocketAddr,
+    req: Arc<proxy::ReplayableRequest>,
+    tried: Arc<Mutex<Vec<String>>>,
+    first: BoxFut,
+) -> BoxFut {
+    let after = match settings.hedge_after {
+        Some(after) if settings.retry_methods.allows(req.method()) => after,
+        _ => return first,
+    };
+    let mut attempts = vec![first];
+    for hedge in 1..=settings.hedge_max {
+        let settings = settings.clone();
+        let req = req.clone();
+        let tried = tried.clone();
+        let hedged_at = Instant::now() + after * hedge;
+        attempts.push(Box::new(Delay::new(hedged_at).then(move |_| {
+            let mut request = req.to_request();
+            let client = forwarded::ClientIp::of(&request).unwrap_or_else(|| remote_addr.ip());
+            let mut tried = tried.lock().unwrap();
+            // a hedge to a backend that is already working on the request would not help
+            let backend = match settings.balancer.select_untried(client, &tried) {
+                Some(backend) if settings.retry_budget.try_retry() => backend,
+                _ => return Box::new(future::empty()) as BoxFut,
+            };
+            tried.push(backend.url.clone());
+            log!(
+                "[{}] {}Hedge {}{} after {}ms without a response",
+                Utc::now(),
+                logging::RequestTag::of(&request),
+                backend.url,
+                request.uri(),
+                (after * hedge).as_millis()
+            );
+            if settings.rewrite_host {
+                request.headers_mut().insert(HOST, backend.host.clone());
+            }
+            let in_flight = backend.start();
+            counted(
+                in_flight,
+                call_upstream(&settings, remote_addr, &req, backend.url.as_str(), request),
+            )
+        })) as BoxFut);
+    }
+    Box::new(
+        future::select_all(attempts)
+            .map(|(response, _, _)| response)
+            .map_err(|(error, _, _)| error),
+    )
+}
+
+/// Re-sends the request to wherever the upstream redirects it, within the redirect policy.
+fn follow_redirects(
+    settings: Arc<Settings>,
+    remote_addr: SocketAddr,
+    req: Arc<proxy::ReplayableRequest>,
+    origin: String,
+    current: Uri,
+    attempt: BoxFut,
+    remaining: usize,
+) -> BoxFut {
+    Box::new(attempt.and_then(move |response| {
+        let policy = match &settings.redirects {
+            Some(policy) if redirect::is_redirect(response.status()) => policy,
+            _ => return Box::new(future::ok(response)) as BoxFut,
+        };
+        let location = match redirect::location(&response, &current) {
+            Some(location) => location,
+            None => return Box::new(future::ok(response)),
+        };
+        let target = redirect::origin(&location);
+        if target != origin && !policy.cross_origin {
+            // let the client decide whether to go somewhere else
+            return Box::new(future::ok(response));
+        }
+        let req = match redirect::resend(policy.method_change, response.status(), req.method()) {
+            Some(redirect::Resend::Unchanged) => req.clone(),
+            Some(redirect::Resend::AsGet) => Arc::new(req.as_get()),
+            None => return Box::new(future::ok(response)),
+        };
+        let mut request = req.to_request();
+        let tag = logging::RequestTag::of(&request);
+        if remaining == 0 {
+            log_error!("{}Error: more than {} upstream redirects", tag, policy.max);
+            return Box::new(future::ok(errors::ErrorStyle::of(&request).response(
+                StatusCode::BAD_GATEWAY,
+                "too many upstream redirects",
+                errors::ErrorFormat::Text,
+            )));
+        }
+        log!(
+            "[{}] {}Redirect {} after HTTP/{}",
+            Utc::now(),
+            tag,
+            location,
+            response.status().as_u16()
+        );
+        *request.uri_mut() = match location.path_and_query() {
+            Some(path) => Uri::from_str(path.as_str()).unwrap(),
+            None => Uri::from_static("/"),
+        };
+        if target != origin || settings.rewrite_host {
+            if let Some(authority) = location.authority_part() {
+                let host = HeaderValue::from_str(authority.as_str()).unwrap();
+                request.headers_mut().insert(HOST, host);
+            }
+        }
+        let attempt = call(&settings, remote_addr, target.as_str(), request);
+        follow_redirects(
+            settings.clone(),
+            remote_addr,
+            req.clone(),
+            origin.clone(),
+            location,
+            attempt,
+            remaining - 1,
+        )
+    }))
+}
+
+/// How long the upstream has to send the response headers for `req`.
+fn header_timeout<B>(settings: &Settings, req: &Request<B>) -> Option<proxy::HeaderTimeout> {
+    if settings.upstream_grpc_timeout {
+        if let Some(timeout) = grpc::timeout(req.headers()) {
+            return Some(proxy::HeaderTimeout::Grpc(timeout));
+        }
+    }
+    let global = match &settings.adaptive_timeout {
+        Some(adaptive) => Some(adaptive.current()),
+        None => settings.upstream_header_timeout,
+    };
+    config::effective_timeout(req.extensions().get::<config::RouteTimeout>(), global)
+        .map(proxy::HeaderTimeout::Http)
+}
+
+/// Whether `req` is a gRPC call to stream through, which may be a long lived bidirectional
+/// stream whose request body must not be waited for.
+fn streams_grpc<B>(settings: &Settings, req: &Request<B>) -> bool {
+    settings.upstream_grpc_streaming && grpc::is_grpc(req.headers())
+}
+
+/// The client to send `req` upstream with.
+fn client_for<B>(
+    settings: &Settings,
+    remote_addr: SocketAddr,
+    req: &Request<B>,
+) -> proxy::HttpClient {
+    match &settings.proxy_protocol_clients {
+        Some(clients) => {
+            // the port only means something if the client connected to us directly
+            let source = match forwarded::ClientIp::of(req) {
+                Some(ip) if ip != remote_addr.ip() => SocketAddr::new(ip, 0),
+                _ => remote_addr,
+            };
+            clients.client(source)
+        }
+        None => settings.client.read().unwrap().clone(),
+    }
+}
+
+/// Sends `request` to `url`, feeding how long the upstream took to respond to the adaptive
+/// timeout.
+///
+/// Only the upstream call itself is timed, from when it is first polled. A request that ran
+/// out of time counts as taking the timeout, as it took at least that long.
+fn call(settings: &Settings, remote_addr: SocketAddr, url: &str, request: Request<Body>) -> BoxFut {
+    let timeout = header_timeout(settings, &request);
+    let attempt = proxy::call(
+        &client_for(settings, remote_addr, &request),
+        remote_addr.ip(),
+        url,
+        request,
+        timeout,
+    );
+    let adaptive = match settings.adaptive_timeout.clone() {
+        Some(adaptive) => adaptive,
+        None => return attempt,
+    };
+    Box::new(future::lazy(move || {
+        let started = Instant::now();
+        attempt.map(move |r| {
+            if r.extensions().get::<proxy::TimedOut>().is_some() {
+                if let Some(timeout) = timeout {
+                    adaptive.record(timeout.duration());
+                }
+            } else if r.extensions().get::<proxy::ConnectFailed>().is_none() {
+                // a request that never reached the upstream says nothing about its latency
+                adaptive.record(started.elapsed());
+            }
+            r
+        })
+    }))
+}
+
+/// Makes one attempt at sending a replayable request to `url`, following any redirects.
+fn call_upstream(
+    settings: &Arc<Settings>,
+    remote_addr: SocketAddr,
+    req: &Arc<proxy::ReplayableRequest>,
+    url: &str,
+    request: Request<Body>,
+) -> BoxFut {
+    let current = Uri::from_str(format!("{}{}", url, request.uri()).as_str()).ok();
+    let attempt = call(settings, remote_addr, url, request);
+    match (&settings.redirects, current) {
+        (Some(policy), Some(current)) => follow_redirects(
+            settings.clone(),
+            remote_addr,
+            req.clone(),
+            redirect::origin(&current),
+            current,
+            attempt,
+            policy.max,
+        ),
+        _ => attempt,
+    }
+}
+
+/// Counts an attempt against its backend until its response has been sent.
+fn counted(in_flight: balancer::InFlight, attempt: BoxFut) -> BoxFut {
+    Box::new(attempt.map(move |r| balancer::hold(r, in_flight)))
+}
+
+/// Sends the request to the selected backend, failing over to the other targets if configured.
+///
+/// `in_flight` counts the request against the selected backend, while any other backends
+/// that are tried count their own attempts.
+fn forward(
+    settings: &Arc<Settings>,
+    remote_addr: SocketAddr,
+    url: &str,
+    in_flight: balancer::InFlight,
+    req: Request<Body>,
+) -> BoxFut {
+    if let Some(fake) = &settings.fake_upstream {
+        return fake.respond();
+    }
+    if (settings.failover.is_empty()
+        && settings.redirects.is_none()
+        && settings.connect_retries == 0
+        && settings.hedge_after.is_none())
+        || streams_grpc(settings, &req)
+    {
+        return counted(in_flight, call(settings, remote_addr, url, req));
+    }
+    if !settings.failover.is_empty()
+        || settings.hedge_after.is_some()
+        || settings.connect_retries > 0
+    {
+        settings.retry_budget.record_request();
+    }
+    let settings = settings.clone();
+    let url = url.to_string();
+    let limit = settings.request_buffer_max_bytes;
+    Box::new(
+        proxy::ReplayableRequest::buffer(req, limit).and_then(move |req| {
+            let req = match req {
+                proxy::Buffered::Replayable(req) => Arc::new(req),
+                proxy::Buffered::TooLarge(req) => {
+                    log!(
+                        "[{}] {}Sending {} once as its body is too large to replay",
+                        Utc::now(),
+                        logging::RequestTag::of(&req),
+                        req.uri()
+                    );
+                    return counted(in_flight, call(&settings, remote_addr, url.as_str(), req));
+                }
+            };
+            let first = counted(
+                in_flight,
+                call_upstream(&settings, remote_addr, &req, url.as_str(), req.to_request()),
+            );
+            let tried = Arc::new(Mutex::new(vec![url.clone()]));
+            let first = hedge(
+                settings.clone(),
+                remote_addr,
+                req.clone(),
+                tried.clone(),
+                first,
+            );
+            let first = retry_connect(settings.clone(), remote_addr, req.clone(), tried, first, 0);
+            failover(settings, remote_addr, req, first, 0)
+        }),
+    )
+}
+
+/// Runs a request transform unless an earlier one has already answered the request.
+fn then_transform<F, T>(req: ReqFut, step: F) -> ReqFut
+where
+    F: FnOnce(Request<Body>) -> T + Send + 'static,
+    T: Future<Item = Transformed, Error = hyper::Error> + Send + 'static,
+{
+    Box::new(req.and_then(move |req| match req {
+        Transformed::Forward(req) => future::Either::A(step(req)),
+        Transformed::Respond(response) => {
+            future::Either::B(future::ok(Transformed::Respond(response)))
+        }
+    }))
+}
+
+/// The responses that steps changing the response body must leave as the upstream sent them.
+#[derive(Clone, Copy)]
+struct Passthrough {
+    errors: bool,
+    /// gRPC responses, whose trailers would be lost by rebuilding the body.
+    grpc: bool,
+}
+
+impl Passthrough {
+    fn applies<B>(self, response: &Response<B>) -> bool {
+        (self.errors
+            && (response.status().is_client_error() || response.status().is_server_error()))
+            || (self.grpc && grpc::is_grpc(response.headers()))
+    }
+}
+
+/// Wraps a step that changes the response body so that it leaves alone the responses that
+/// are to be passed through as the upstream sent them.
+fn unless_passed_through<F>(
+    passthrough: Passthrough,
+    step: F,
+) -> impl FnOnce(Response<Body>) -> BoxFut
+where
+    F: FnOnce(Response<Body>) -> BoxFut,
+{
+    move |r| {
+        if passthrough.applies(&r) {
+            Box::new(future::ok(r))
+        } else {
+            step(r)
+        }
+    }
+}
+
+/// The HTTP/1 header buffer size option `name`, which hyper needs to be at least 8KiB.
+fn max_header_size(matches: &Matches, name: &str) -> Option<usize> {
+    let v = matches.opt_str(name)?;
+    match v.parse::<usize>() {
+        Ok(v) if v >= 8192 => Some(v),
+        _ => panic!("--{} is supposed to be an integer of at least 8192", name),
+    }
+}
+
+/// The HTTP/2 flow control window option `name`, which the protocol caps at 2^31-1.
+fn h2_window(matches: &Matches, name: &str) -> Option<u32> {
+    let v = matches.opt_str(name)?;
+    match v.parse::<u32>() {
+        Ok(v) if v > 0 && v <= i32::MAX as u32 => Some(v),
+        _ => panic!(
+            "--{} is supposed to be an integer between 1 and {}",
+            name,
+            i32::MAX
+        ),
+    }
+}
+
+fn route(settings: &Arc<Settings>, remote_addr: SocketAddr, mut req: Request<Body>) -> BoxFut {
+    let source_prefix = settings.source_prefix.as_str();
+    let tag = logging::RequestTag::of(&req);
+    let style = errors::ErrorStyle::of(&req);
+    if req.uri().path().starts_with(source_prefix) {
+        let started = Instant::now();
+        let request_uri = req.uri().to_string();
+        let tapped = settings.tap.as_ref().and_then(|tap| tap.begin(&req));
+        // HTTP/2 clients send the host as the :authority of the URI rather than a header
+        let original_host = match req.headers().get(HOST) {
+            Some(host) => Some(host.clone()),
+            None => req
+                .uri()
+                .authority_part()
+                .and_then(|a| HeaderValue::from_str(a.as_str()).ok()),
+        };
+        let accept_encoding = req.headers().get(ACCEPT_ENCODING).cloned();
+        let config = settings.config.read().unwrap().clone();
+        if let Some(timeout) = config.route_timeout(req.uri().path()) {
+            req.extensions_mut().insert(timeout);
+        }
+        let strip_query = config.strip_query_string
+            || config
+                .strip_query_string_paths
+                .iter()
+                .any(|glob| glob.matches(req.uri().path()));
+        let forward_uri = match req.uri().query() {
+            Some(_) if strip_query => req.uri().path().replace(source_prefix, "/"),
+            Some(query) => format!("{}?{}", req.uri().path().replace(source_prefix, "/"), query),
+            None => req.uri().path().replace(source_prefix, "/"),
+        };
+        let host_name = original_host
+            .as_ref()
+            .and_then(|h| h.to_str().ok())
+            .map(vhost::host_name);
+        let backend = match host_name.and_then(|h| config.host_rules.select(h)) {
+            Some(backend) => backend,
+            None => match settings
+                .balancer
+                .select(forwarded::ClientIp::of(&req).unwrap_or_else(|| remote_addr.ip()))
+            {
+                Some(backend) => backend,
+                None => {
+                    log!("[{}] {}{} HTTP/404", Utc::now(), tag, request_uri);
+                    return not_found(&style);
+                }
+            },
+        };
+        let backend_label = settings.backend_labels.as_ref().map(|labels| {
+            metrics::BackendLabel(labels.get(&backend.url).unwrap_or(&backend.url).clone())
+        });
+        log!(
+            "[{}] {}{} Proxy {}{}",
+            Utc::now(),
+            tag,
+            request_uri,
+            backend.url,
+            forward_uri
+        );
+        *req.uri_mut() = Uri::from_str(forward_uri.as_str()).unwrap();
+        if let Some(authority) = &settings.upstream_authority {
+            req.headers_mut().insert(HOST, authority.clone());
+        } else if settings.rewrite_host {
+            req.headers_mut().insert(HOST, backend.host.clone());
+        } else if settings.preserve_host {
+            if let Some(host) = original_host {
+                req.headers_mut().insert(HOST, host);
+            }
+        }
+        if websocket::is_upgrade(&req) {
+            if !settings.websocket_subprotocols.is_empty() {
+                let unsupported =
+                    websocket::unsupported_subprotocols(&req, &settings.websocket_subprotocols);
+                if !unsupported.is_empty() {
+                    log!("[{}] {}{} HTTP/400", Utc::now(), tag, request_uri);
+                    return Box::new(future::ok(bad_request(
+                        &style,
+                        format!(
+                            "Unsupported WebSocket subprotocol: {}",
+                            unsupported.join(", ")
+                        ),
+                    )));
+                }
+            }
+            req = websocket::Handshake::begin(req, settings.websocket_max_frame_size);
+        }
+        let grpc_web = if settings.upstream_grpc_web {
+            grpc_web::Format::of(req.headers())
+        } else {
+            None
+        };
+        if let Some(format) = grpc_web {
+            req = grpc_web::to_grpc(req, format);
+        }
+        jwt::apply(&settings.jwt_claim_headers, req.headers_mut());
+        if let Some(name) = &settings.deadline_header {
+            if let Some(remaining) = deadline::remaining(req.headers(), name) {
+                if remaining.as_millis() == 0 {
+                    log!(
+                        "[{}] {}{} HTTP/504 (deadline passed)",
+                        Utc::now(),
+                        tag,
+                        request_uri
+                    );
+                    if grpc::is_grpc(req.headers()) {
+                        return Box::new(future::ok(grpc::deadline_exceeded()));
+                    }
+                    return Box::new(future::ok(style.response(
+                        StatusCode::GATEWAY_TIMEOUT,
+                        "the request deadline has passed",
+                        errors::ErrorFormat::Text,
+                    )));
+                }
+                deadline::propagate(req.headers_mut(), remaining);
+            }
+        }
+        if let Some(max) = settings.upstream_grpc_max_timeout {
+            if grpc::is_grpc(req.headers()) {
+                grpc::cap_timeout(req.headers_mut(), max);
+            }
+        }
+        if let Some(authorization) = &settings.upstream_authorization {
+            req.headers_mut()
+                .insert(AUTHORIZATION, authorization.clone());
+        }
+        if let Some(timestamp) = &settings.request_timestamp {
+            timestamp.apply(req.headers_mut());
+        }
+        let mut body_exceeded = None;
+        if let Some(limit) = settings.request_body_max_bytes {
+            let content_length = req
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+            if content_length.is_some_and(|length| length > limit) {
+                log!("[{}] {}{} HTTP/413", Utc::now(), tag, request_uri);
+                return Box::new(future::ok(payload_too_large(&style)));
+            }
+            let (parts, body) = req.into_parts();
+            let bounded = body::BoundedBody::new(body, limit);
+            body_exceeded = Some(bounded.exceeded());
+            req = Request::from_parts(parts, bounded.into_body());
+        }
+        let permit = match settings.concurrency.try_acquire() {
+            Some(permit) => permit,
+            None => {
+                log!("[{}] {}{} HTTP/503", Utc::now(), tag, request_uri);
+                return Box::new(future::ok(service_unavailable(&style)));
+            }
+        };
+        if let Some(breaker) = &settings.error_rate_breaker {
+            if !breaker.admit() {
+                log!(
+                    "[{}] {}{} HTTP/503 (shedding)",
+                    Utc::now(),
+                    tag,
+                    request_uri
+                );
+                return Box::new(future::ok(upstream_shedding(&style)));
+            }
+        }
+        let in_flight = backend.start();
+        let rewrite_json = !settings.json_rewrites.is_empty() && json_body::is_json(&req);
+        let compress = settings
+            .request_compression
+            .as_ref()
+            .is_some_and(|c| c.applies(backend.url.as_str(), &req));
+        let future = if (rewrite_json
+            || compress
+            || settings.request_transform.is_some()
+            || settings.request_signer.is_some())
+            && !streams_grpc(settings, &req)
+        {
+            let settings = settings.clone();
+            let url = backend.url.clone();
+            let limit = settings.request_buffer_max_bytes;
+            // the transforms resolve to `None` when the body is too large for them to buffer
+            let too_large = {
+                let tag = tag.clone();
+                let style = style.clone();
+                let request_uri = request_uri.clone();
+                move || {
+                    log!("[{}] {}{} HTTP/413", Utc::now(), tag, request_uri);
+                    Transformed::Respond(payload_too_large(&style))
+                }
+            };
+            let req: ReqFut = if rewrite_json {
+                let too_large = too_large.clone();
+                Box::new(
+                    json_body::rewrite(req, settings.json_rewrites.clone(), limit)
+                        .map(move |req| req.map_or_else(too_large, Transformed::Forward)),
+                )
+            } else {
+                Box::new(future::ok(Transformed::Forward(req)))
+            };
+            let req = match settings.request_transform.clone() {
+                Some(transform) => {
+                    let tag = tag.clone();
+                    let too_large = too_large.clone();
+                    then_transform(req, move |req| {
+                        transform
+                            .apply(req, tag, limit)
+                            .map(move |req| req.unwrap_or_else(too_large))
+                    })
+                }
+                None => req,
+            };
+            let req = match settings.request_compression.clone() {
+                Some(compression) if compress => {
+                    let too_large = too_large.clone();
+                    then_transform(req, move |req| {
+                        compression
+                            .compress(req, limit)
+                            .map(move |req| req.map_or_else(too_large, Transformed::Forward))
+                    })
+                }
+                _ => req,
+            };
+            let req = match settings.request_signer.clone() {
+                Some(signer) => then_transform(req, move |req| {
+                    signer
+                        .sign(req)
+                        .map(move |req| req.map_or_else(too_large, Transformed::Forward))
+                }),
+                None => req,
+            };
+            Box::new(req.and_then(move |req| match req {
+                Transformed::Forward(req) => {
+                    forward(&settings, remote_addr, url.as_str(), in_flight, req)
+                }
+                Transformed::Respond(response) => Box::new(future::ok(response)),
+            }))
+        } else {
+            forward(settings, remote_addr, backend.url.as_str(), in_flight, req)
+        };
+        let future: BoxFut = Box::new(future.map(move |r| {
+            drop(permit);
+            r
+        }));
+        let future: BoxFut = match grpc_web {
+            Some(format) => Box::new(future.map(move |r| grpc_web::to_grpc_web(r, format))),
+            None => future,
+        };
+        let future: BoxFut = match settings.error_rate_breaker.clone() {
+            Some(breaker) => Box::new(future.map(move |r| {
+                breaker.record(r.status());
+                r
+            })),
+            None => future,
+        };
+        let future: BoxFut = match settings.request_timing.clone() {
+            Some(timing) => {
+                let tag = tag.clone();
+                let request_uri = request_uri.clone();
+                Box::new(future.map(move |r| timing.record(r, started, tag, request_uri)))
+            }
+            None => future,
+        };
+        let future: BoxFut = match settings.request_compression.clone() {
+            Some(compression) => {
+                let url = backend.url.clone();
+                Box::new(future.map(move |r| {
+                    compression.learn(url.as_str(), &r);
+                    r
+                }))
+            }
+            None => future,
+        };
+        let passthrough = Passthrough {
+            errors: settings.upstream_error_body_passthrough,
+            grpc: settings.upstream_grpc_status_passthrough,
+        };
+        let future: BoxFut = match settings.upstream_max_response_size {
+            Some(limit) => {
+                let tag = tag.clone();
+                let style = style.clone();
+                Box::new(
+                    future.and_then(unless_passed_through(passthrough, move |r| {
+                        body::limit_response(r, limit, tag, style)
+                    })),
+                )
+            }
+            None => future,
+        };
+        let future: BoxFut =
+            if settings.decompress_brotli && !encoding::accepts(accept_encoding.as_ref(), "br") {
+                let gzip = encoding::accepts(accept_encoding.as_ref(), "gzip");
+                let limit = settings
+                    .upstream_max_response_size
+                    .unwrap_or(encoding::DEFAULT_MAX_DECODED_SIZE);
+                let tag = tag.clone();
+                let style = style.clone();
+                Box::new(
+                    future.and_then(unless_passed_through(passthrough, move |r| {
+                        encoding::decode_brotli(r, gzip, limit, tag, style)
+                    })),
+                )
+            } else {
+                future
+            };
+        let future: BoxFut = match &settings.json_envelope {
+            Some(envelope) => {
+                let envelope = envelope.clone();
+                let tag = tag.clone();
+                Box::new(
+                    future.and_then(unless_passed_through(passthrough, move |r| {
+                        envelope.wrap(r, tag)
+                    })),
+                )
+            }
+            None => future,
+        };
+        let future: BoxFut = match &settings.script_tag {
+            Some(script_tag) => {
+                let script_tag = script_tag.clone();
+                Box::new(
+                    future.and_then(unless_passed_through(passthrough, move |r| {
+                        script_tag.inject(r)
+                    })),
+                )
+            }
+            None => future,
+        };
+        let future: BoxFut = match body_exceeded {
+            Some(exceeded) => {
+                let request_uri = request_uri.clone();
+                let style = style.clone();
+                Box::new(future.then(move |r| {
+                    if exceeded.load(Ordering::SeqCst) {
+                        log!("[{}] {}{} HTTP/413", Utc::now(), tag, request_uri);
+                        Ok(payload_too_large(&style))
+                    } else {
+                        r
+                    }
+                }))
+            }
+            None => future,
+        };
+        let future: BoxFut = match settings.max_age_cap {
+            Some(cap) => Box::new(future.map(move |mut r| {
+                headers::cap_max_age(r.headers_mut(), cap);
+                r
+            })),
+            None => future,
+        };
+        let future: BoxFut = match settings.cookie_rewrite.clone() {
+            Some(cookie_rewrite) => Box::new(future.map(move |mut r| {
+                cookie_rewrite.apply(r.headers_mut());
+                r
+            })),
+            None => future,
+        };
+        let future: BoxFut = if config.response_headers.is_empty() {
+            future
+        } else {
+            Box::new(future.map(move |mut r| {
+                config.response_headers.apply(r.headers_mut());
+                r
+            }))
+        };
+        let future: BoxFut = match (settings.tap.clone(), tapped) {
+            (Some(tap), Some(tapped)) => Box::new(future.map(move |r| {
+                tap.finish(tapped, &r);
+                r
+            })),
+            _ => future,
+        };
+        match backend_label {
+            Some(label) => Box::new(future.map(move |mut r| {
+                r.extensions_mut().insert(label);
+                r
+            })),
+            None => future,
+        }
+    } else if req.uri().path().eq(settings.source_match.as_str()) {
+        log!(
+            "[{}] {}{} HTTP/301 Location: {}",
+            Utc::now(),
+            tag,
+            req.uri(),
+            source_prefix
+        );
+        redirect(source_prefix)
+    } else if req.headers().contains_key("X-Proxy-Debug") {
+        log!("[{}] {}{} Debug {:?}", Utc::now(), tag, req.uri(), req);
+        debug_request(req)
+    } else {
+        log!("[{}] {}{} HTTP/404", Utc::now(), tag, req.uri());
+        not_found(&style)
+    }
+}
+
+fn main() {
+    const VERSION: &str = env!("CARGO_PKG_VERSION");
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let opts = create_options();
+    let mut matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(f) => panic!("{}", f),
+    };
+    let config_file = matches.opt_str("config");
+    if let Some(path) = &config_file {
+        matches = match config::parse(&opts, &args[1..], path) {
+            Ok(m) => m,
+            Err(e) => panic!("{}", e),
+        };
+    }
+    if matches.opt_present("h") {
+        print_usage(&program, opts);
+        return;
+    }
+    if matches.opt_present("V") {
+        println!("{}", VERSION);
+        return;
+    }
+
+    logging::set_silent(matches.opt_present("silent"));
+
+    let port = match matches.opt_str("p") {
+        Some(v) => match v.parse::<u16>() {
+            Ok(v) => v,
+            Err(_) => panic!("Port is supposed to be an integer in the range 0-65535"),
+        },
+        None => 8080,
+    };
+
+    let fake_upstream = if matches.opt_present("fake-upstream-status")
+        || matches.opt_present("fake-upstream-body")
+        || matches.opt_present("fake-upstream-headers")
+        || matches.opt_present("fake-upstream-delay")
+    {
+        let mut headers = Vec::new();
+        for spec in matches.opt_strs("fake-upstream-headers") {
+            match headers::parse_header(spec.as_str()) {
+                Ok(header) => headers.push(header),
+                Err(e) => panic!("{}", e),
+            }
+        }
+        Some(fake::FakeUpstream {
+            status: match matches.opt_str("fake-upstream-status") {
+                Some(v) => match v.parse::<StatusCode>() {
+                    Ok(status) => status,
+                    Err(_) => panic!("Fake upstream status is supposed to be an HTTP status code"),
+                },
+                None => StatusCode::OK,
+            },
+            body: matches.opt_str("fake-upstream-body").unwrap_or_default(),
+            headers,
+            delay: match matches.opt_str("fake-upstream-delay") {
+                Some(v) => match v.parse::<u64>() {
+                    Ok(v) => Some(Duration::from_millis(v)),
+                    Err(_) => {
+                        panic!("Fake upstream delay is supposed to be a non-negative integer")
+                    }
+                },
+                None => None,
+            },
+        })
+    } else {
+        None
+    };
+    let mut targets = matches.opt_strs("t");
+    if targets.is_empty() && fake_upstream.is_some() {
+        // nothing is ever sent here, but it is what the logs and Host rewriting see
+        targets.push("http://fake-upstream".to_string());
+    }
+    if targets.is_empty() && !matches.opt_present("sni-rewrite") {
+        panic!("You must provide the target URL");
+    }
+    let mut backends = Vec::new();
+    for url in targets {
+        match balancer::Backend::parse(url.as_str()) {
+            Ok(backend) => backends.push(backend),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    let max_concurrent_requests = match matches.opt_str("max-concurrent-requests") {
+        Some(v) => match v.parse::<usize>() {
+            Ok(v) => v,
+            Err(_) => panic!("Max concurrent requests is supposed to be a non-negative integer"),
+        },
+        None => 0,
+    };
+    let mut json_rewrites = Vec::new();
+    for spec in matches.opt_strs("rewrite-request-body-json") {
+        match json_body::JsonRewrite::parse(spec.as_str()) {
+            Ok(rewrite) => json_rewrites.push(rewrite),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    let json_envelope = if matches.opt_present("wrap-json-response")
+        || matches.opt_present("wrap-json-array-response")
+    {
+        let mut envelope = json_body::JsonEnvelope::default();
+        envelope.arrays = matches.opt_present("wrap-json-array-response");
+        for spec in matches.opt_strs("json-wrap-meta-field") {
+            if let Err(e) = envelope.add_meta_field(spec.as_str()) {
+                panic!("{}", e);
+            }
+        }
+        Some(Arc::new(envelope))
+    } else {
+        None
+    };
+    let request_signer = match matches.opt_str("request-signing-secret") {
+        Some(secret) => {
+            let header = match matches.opt_str("request-signing-header") {
+                Some(v) => match HeaderName::from_bytes(v.as_bytes()) {
+                    Ok(header) => header,
+                    Err(_) => panic!("Request signing header is supposed to be a header name"),
+                },
+                None => HeaderName::from_static("x-proxy-signature"),
+            };
+            let max_body_bytes = match matches.opt_str("request-signing-max-body-bytes") {
+                Some(v) => match v.parse::<u64>() {
+                    Ok(v) => v,
+                    Err(_) => panic!(
+                        "Request signing max body bytes is supposed to be a non-negative integer"
+                    ),
+                },
+                None => 1024 * 1024,
+            };
+            Some(Arc::new(signing::RequestSigner::new(
+                secret.as_str(),
+                header,
+                max_body_bytes,
+            )))
+        }
+        None => None,
+    };
+    let request_transform = match matches.opt_str("request-body-transform-wasm") {
+        Some(path) => {
+            let fuel = match matches.opt_str("wasm-fuel-limit") {
+                Some(v) => match v.parse::<u64>() {
+                    Ok(v) if v > 0 => Some(v),
+                    _ => panic!("WASM fuel limit is supposed to be a positive integer"),
+                },
+                None => None,
+            };
+            match wasm::RequestTransform::load(path.as_str(), fuel) {
+                Ok(transform) => Some(Arc::new(transform)),
+                Err(e) => panic!("{}", e),
+            }
+        }
+        None => None,
+    };
+    let request_compression = if matches.opt_present("upstream-compress-request") {
+        let min_bytes = match matches.opt_str("upstream-compress-request-min-bytes") {
+            Some(v) => match v.parse::<usize>() {
+                Ok(v) => v,
+                Err(_) => panic!(
+                    "Upstream compress request min bytes is supposed to be a non-negative integer"
+                ),
+            },
+            None => 1024,
+        };
+        Some(Arc::new(encoding::RequestCompression::new(min_bytes)))
+    } else {
+        None
+    };
+    let mut jwt_claim_headers = Vec::new();
+    for spec in matches.opt_strs("extract-jwt-claim-unsecured") {
+        match jwt::ClaimHeader::parse(spec.as_str()) {
+            Ok(claim_header) => jwt_claim_headers.push(claim_header),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    let config = match config::Config::from_matches(&matches) {
+        Ok(config) => Arc::new(RwLock::new(Arc::new(config))),
+        Err(e) => panic!("{}", e),
+    };
+    let retry_count = match matches.opt_str("retry-count") {
+        Some(v) => match v.parse::<u32>() {
+            Ok(v) => v,
+            Err(_) => panic!("Retry count is supposed to be a non-negative integer"),
+        },
+        None => 0,
+    };
+    let retry_methods = match (
+        matches.opt_present("upstream-retry-safe-methods-only"),
+        matches.opt_present("upstream-retry-idempotent-methods"),
+        matches.opt_present("upstream-retry-all-methods"),
+    ) {
+        (_, false, false) => retry::RetryMethods::Safe,
+        (false, true, false) => retry::RetryMethods::Idempotent,
+        (false, false, true) => retry::RetryMethods::All,
+        _ => panic!("You can only specify one of --upstream-retry-safe-methods-only, --upstream-retry-idempotent-methods and --upstream-retry-all-methods"),
+    };
+    let connect_retries = if matches.opt_present("upstream-retry-on-connect-error") {
+        if retry_count == 0 {
+            panic!(
+                "You must provide a --retry-count above 0 to use --upstream-retry-on-connect-error"
+            );
+        }
+        retry_count
+    } else {
+        0
+    };
+    let mut failover = Vec::new();
+    for url in matches.opt_strs("upstream-failover-url") {
+        match balancer::Backend::parse(url.as_str()) {
+            Ok(backend) => failover.push(backend),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    let strategy = match matches.opt_str("upstream-balancer") {
+        Some(v) => match v.parse::<balancer::Strategy>() {
+            Ok(strategy) => strategy,
+            Err(e) => panic!("{}", e),
+        },
+        None => balancer::Strategy::RoundRobin,
     };
 
-    let target: Box<String> = Box::from(match matches.opt_str("t") {
-        Some(v) => v.trim_end_matches('/').to_string(),
-        None => panic!("You must provide the target URL"),
-    });
-
     let source: Box<String> = Box::from(match matches.opt_str("s") {
         Some(v) => v.trim_matches('/').to_string(),
         None => "".to_string(),
     });
 
-    let cache: Option<Box<String>> = match matches.opt_str("c") {
-        Some(v) => Option::Some(Box::from(v)),
-        None => Option::None,
+    let request_body_max_bytes = match matches.opt_str("request-body-max-bytes") {
+        Some(v) => match v.parse::<u64>() {
+            Ok(v) => Some(v),
+            Err(_) => panic!("Request body max bytes is supposed to be a non-negative integer"),
+        },
+        None => None,
+    };
+
+    let request_buffer_max_bytes = match matches.opt_str("request-buffer-max-bytes") {
+        Some(v) => match v.parse::<u64>() {
+            Ok(v) => v,
+            Err(_) => panic!("Request buffer max bytes is supposed to be a non-negative integer"),
+        },
+        None => 10 * 1024 * 1024,
+    };
+
+    let mut static_dirs = Vec::new();
+    for spec in matches.opt_strs("static-dir") {
+        match static_files::StaticDir::parse(spec.as_str()) {
+            Ok(dir) => static_dirs.push(dir),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    let preserve_host = matches.opt_present("preserve-host");
+    let rewrite_host = matches.opt_present("rewrite-host");
+    if preserve_host && rewrite_host {
+        panic!("You cannot specify both --preserve-host and --rewrite-host");
+    }
+    let basic_auth_credentials = match (
+        matches.opt_str("upstream-basic-auth"),
+        matches.opt_str("upstream-basic-auth-file"),
+    ) {
+        (Some(_), Some(_)) => {
+            panic!("You cannot specify both --upstream-basic-auth and --upstream-basic-auth-file")
+        }
+        (Some(credentials), None) => Some(credentials),
+        (None, Some(path)) => match fs::read_to_string(&path) {
+            Ok(contents) => Some(contents.lines().next().unwrap_or("").to_string()),
+            Err(e) => panic!("Could not read {}: {}", path, e),
+        },
+        (None, None) => None,
+    };
+    let upstream_authorization = basic_auth_credentials.map(
+        |credentials| match headers::basic_auth(credentials.as_str()) {
+            Ok(value) => value,
+            Err(e) => panic!("{}", e),
+        },
+    );
+    let upstream_authority = match matches.opt_str("upstream-http-authority") {
+        Some(_) if preserve_host || rewrite_host => panic!(
+            "You cannot specify --upstream-http-authority with --preserve-host or --rewrite-host"
+        ),
+        Some(v) => match v.parse::<Authority>() {
+            Ok(_) => Some(HeaderValue::from_str(v.as_str()).unwrap()),
+            Err(_) => panic!("Upstream HTTP authority is supposed to be a HOST[:PORT]"),
+        },
+        None => None,
+    };
+
+    let upstream_tls = tls::UpstreamTls {
+        ca_cert: matches.opt_str("upstream-ca-cert"),
+        client_cert: matches.opt_str("upstream-client-cert"),
+        client_key: matches.opt_str("upstream-client-key"),
+        skip_hostname_verification: match matches.opt_str("upstream-tls-verify-hostname") {
+            Some(v) => match v.parse::<bool>() {
+                Ok(verify) => !verify,
+                Err(_) => panic!("Upstream TLS verify hostname is supposed to be true or false"),
+            },
+            None => false,
+        },
+        cipher_suites: match matches.opt_str("upstream-tls-cipher-suites") {
+            Some(v) => match tls::parse_cipher_suites(v.as_str()) {
+                Ok(suites) => suites,
+                Err(e) => panic!("{}", e),
+            },
+            None => Vec::new(),
+        },
+        http2: (matches.opt_present("upstream-send-trailers")
+            || matches.opt_present("upstream-grpc-web"))
+            && !matches.opt_present("upstream-http1-only"),
+        versions: match tls::parse_versions(
+            matches
+                .opt_str("upstream-tls-min-version")
+                .as_deref()
+                .unwrap_or("1.2"),
+            matches
+                .opt_str("upstream-tls-max-version")
+                .as_deref()
+                .unwrap_or("1.3"),
+        ) {
+            Ok(versions) => versions,
+            Err(e) => panic!("{}", e),
+        },
+    };
+    if upstream_tls.skip_hostname_verification {
+        for backend in backends.iter().chain(failover.iter()) {
+            if backend.url.starts_with("https:") {
+                log_error!(
+                    "[{}] WARN Not verifying the TLS host name of {}",
+                    Utc::now(),
+                    backend.url
+                );
+            }
+        }
+    }
+    let upstream_grpc_streaming = matches.opt_present("upstream-grpc-reflection-proxy");
+    if upstream_grpc_streaming && !upstream_tls.http2 {
+        panic!("You must provide --upstream-send-trailers without --upstream-http1-only to use --upstream-grpc-reflection-proxy");
+    }
+    if matches.opt_present("upstream-http1-only") {
+        if matches.opt_present("upstream-send-trailers") {
+            log_error!(
+                "[{}] WARN --upstream-send-trailers needs HTTP/2 but --upstream-http1-only is set, request trailers will not be forwarded",
+                Utc::now()
+            );
+        }
+        for backend in backends.iter().chain(failover.iter()) {
+            if backend.url.starts_with("h2c:") {
+                log_error!(
+                    "[{}] WARN Talking HTTP/1.1 to {} because --upstream-http1-only is set",
+                    Utc::now(),
+                    backend.url
+                );
+            }
+        }
+    }
+    if upstream_tls.client_cert.is_some() != upstream_tls.client_key.is_some() {
+        panic!("You must provide both --upstream-client-cert and --upstream-client-key");
+    }
+    let cert_reload_interval = match matches.opt_str("upstream-client-cert-reload-interval") {
+        Some(v) => match v.parse::<u64>() {
+            Ok(v) if v > 0 && upstream_tls.client_cert.is_some() => Some(Duration::from_secs(v)),
+            Ok(_) if upstream_tls.client_cert.is_none() => panic!(
+                "You must provide --upstream-client-cert to use --upstream-client-cert-reload-interval"
+            ),
+            _ => panic!("Client certificate reload interval is supposed to be a positive integer"),
+        },
+        None => None,
+    };
+    let client_config = match upstream_tls.client_config() {
+        Ok(config) => Arc::new(RwLock::new(Arc::new(config))),
+        Err(e) => panic!("{}", e),
+    };
+    let dns_cache_ttl = match matches.opt_str("upstream-dns-cache-ttl") {
+        Some(v) => match v.parse::<u64>() {
+            Ok(v) => Duration::from_secs(v),
+            Err(_) => panic!("DNS cache TTL is supposed to be a non-negative integer"),
+        },
+        None => Duration::from_secs(30),
+    };
+    let mut dns_servers = Vec::new();
+    for spec in matches.opt_strs("upstream-dns-servers") {
+        match dns::parse_server(spec.as_str()) {
+            Ok(server) => dns_servers.push(server),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    let resolver = if dns_servers.is_empty() {
+        dns::CachingResolver::new(dns_cache_ttl)
+    } else {
+        dns::CachingResolver::with_servers(dns_cache_ttl, dns_servers)
+    };
+    let mut connector = connector::Connector::new(resolver.clone(), client_config.clone());
+    if let Some(v) = matches.opt_str("upstream-local-addr") {
+        match v.parse::<IpAddr>() {
+            Ok(addr) => connector.set_local_address(Some(addr)),
+            Err(_) => panic!("Upstream local address is supposed to be an IPv4 or IPv6 address"),
+        }
+    }
+    let interfaces = matches.opt_strs("upstream-interface");
+    if !interfaces.is_empty() {
+        if matches.opt_present("upstream-local-addr") {
+            panic!("Upstream interface cannot be combined with upstream local address");
+        }
+        match connector::InterfaceAddresses::lookup(&interfaces) {
+            Ok(addresses) => connector.set_interfaces(Some(Arc::new(addresses))),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    let keepalive_seconds = |name: &str, what: &str| match matches.opt_str(name) {
+        Some(v) => match v.parse::<u64>() {
+            Ok(v) if v > 0 => Some(Duration::from_secs(v)),
+            _ => panic!("{} is supposed to be a positive integer", what),
+        },
+        None => None,
+    };
+    let tcp_keepalive = connector::TcpKeepalive {
+        idle: keepalive_seconds("upstream-tcp-keepalive-idle", "Upstream TCP keepalive idle"),
+        interval: keepalive_seconds(
+            "upstream-tcp-keepalive-interval",
+            "Upstream TCP keepalive interval",
+        ),
+        count: match matches.opt_str("upstream-tcp-keepalive-count") {
+            Some(v) => match v.parse::<u32>() {
+                Ok(v) if v > 0 => Some(v),
+                _ => panic!("Upstream TCP keepalive count is supposed to be a positive integer"),
+            },
+            None => None,
+        },
+    };
+    if tcp_keepalive.idle.is_some()
+        || tcp_keepalive.interval.is_some()
+        || tcp_keepalive.count.is_some()
+    {
+        if !connector::PROBE_SETTINGS_SUPPORTED
+            && (tcp_keepalive.interval.is_some() || tcp_keepalive.count.is_some())
+        {
+            log_error!(
+                "[{}] WARN This platform only supports setting the TCP keepalive idle time, ignoring the interval and count",
+                Utc::now()
+            );
+        }
+        connector.set_tcp_keepalive(Some(tcp_keepalive));
+    }
+    if let Some(v) = matches.opt_str("upstream-tcp-user-timeout") {
+        match v.parse::<u64>() {
+            Ok(v) if v > 0 => connector.set_tcp_user_timeout(Some(Duration::from_millis(v))),
+            _ => panic!("Upstream TCP user timeout is supposed to be a positive integer"),
+        }
+    }
+    let upstream_idle_timeout = match matches.opt_str("upstream-idle-timeout") {
+        Some(v) => match v.parse::<u64>() {
+            Ok(v) if v > 0 => Duration::from_secs(v),
+            _ => panic!("Upstream idle timeout is supposed to be a positive integer"),
+        },
+        None => Duration::from_secs(90),
+    };
+    // Request trailers only exist in HTTP/2, and hyper forwards them when the body is
+    // streamed through as is, so buffered or rewritten bodies will still lose them.
+    let mut client_builder = Client::builder();
+    client_builder
+        .http2_only(upstream_tls.http2)
+        .keep_alive_timeout(upstream_idle_timeout);
+    if let Some(v) = max_header_size(&matches, "upstream-max-header-size") {
+        client_builder.http1_max_buf_size(v);
+    }
+    client_builder
+        .http2_initial_stream_window_size(h2_window(&matches, "upstream-h2-initial-stream-window"))
+        .http2_initial_connection_window_size(h2_window(
+            &matches,
+            "upstream-h2-initial-connection-window",
+        ));
+    if let Some(v) = matches.opt_str("upstream-pool-max-idle-per-host") {
+        match v.parse::<usize>() {
+            Ok(v) => client_builder.max_idle_per_host(v),
+            Err(_) => {
+                panic!("Upstream pool max idle per host is supposed to be a non-negative integer")
+            }
+        };
+    }
+    let clients = Arc::new(RwLock::new(client_builder.build(connector.clone())));
+    let client_renewal = match matches.opt_str("upstream-pool-max-connection-lifetime") {
+        Some(v) => match v.parse::<u64>() {
+            Ok(v) if v > 0 => Some(proxy::renew_every(
+                clients.clone(),
+                client_builder.clone(),
+                connector.clone(),
+                Duration::from_secs(v),
+            )),
+            _ => {
+                panic!("Upstream pool max connection lifetime is supposed to be a positive integer")
+            }
+        },
+        None => None,
+    };
+    let mut cookie_rewrite = cookies::CookieRewrite::default();
+    for spec in matches.opt_strs("rewrite-cookie-domain") {
+        if let Err(e) = cookie_rewrite.add_domain(spec.as_str()) {
+            panic!("{}", e)
+        }
+    }
+    cookie_rewrite.set_secure(matches.opt_present("add-cookie-secure-flag"));
+    let cookie_rewrite = if cookie_rewrite.is_empty() {
+        None
+    } else {
+        Some(Arc::new(cookie_rewrite))
+    };
+    let proxy_protocol_clients = match matches.opt_str("upstream-proxy-protocol") {
+        Some(v) => match v.parse::<connector::ProxyProtocol>() {
+            Ok(version) => {
+                let mut builder = client_builder.clone();
+                builder.keep_alive(false);
+                Some(proxy::ProxyProtocolClients {
+                    version,
+                    connector: connector.clone(),
+                    builder,
+                })
+            }
+            Err(e) => panic!("{}", e),
+        },
+        None => None,
     };
 
     // This is our socket address...
-    let addr = ([0, 0, 0, 0], port).into();
+    let addr: SocketAddr = ([0, 0, 0, 0], port).into();
+
+    if matches.opt_present("sni-passthrough-mode") {
+        let mut routes = Vec::new();
+        for url in matches.opt_strs("t") {
+            match sni::Route::from_url(url.as_str()) {
+                Ok(route) => routes.push(route),
+                Err(e) => panic!("{}", e),
+            }
+        }
+        let mut rules = vhost::HostRules::default();
+        for spec in matches.opt_strs("sni-rewrite") {
+            if let Err(e) = rules.add(spec.as_str(), sni::Route::from_url) {
+                panic!("{}", e);
+            }
+        }
+        let server = match sni::serve(&addr, routes, rules, resolver) {
+            Ok(server) => server,
+            Err(e) => panic!("error binding to {}: {}", addr, e),
+        };
+        log!("Running SNI passthrough on {:?}", addr);
+        hyper::rt::run(server);
+        return;
+    }
+
+    let keepalive_requests = match matches.opt_str("keepalive-requests") {
+        Some(v) => match v.parse::<usize>() {
+            Ok(v) => v,
+            Err(_) => panic!("Keep-alive requests is supposed to be a non-negative integer"),
+        },
+        None => 0,
+    };
+
+    let upstream_header_timeout = match matches.opt_str("upstream-response-header-timeout") {
+        Some(v) => match v.parse::<u64>() {
+            Ok(v) if v > 0 => Some(Duration::from_millis(v)),
+            _ => panic!("Upstream response header timeout is supposed to be a positive integer"),
+        },
+        None => None,
+    };
+
+    let adaptive_timeout = if matches.opt_present("upstream-adaptive-timeout") {
+        if upstream_header_timeout.is_some() {
+            panic!("You cannot specify both --upstream-adaptive-timeout and --upstream-response-header-timeout");
+        }
+        let multiplier = match matches.opt_str("adaptive-timeout-multiplier") {
+            Some(v) => match v.parse::<f64>() {
+                Ok(v) if v > 0.0 && v.is_finite() => v,
+                _ => panic!("Adaptive timeout multiplier is supposed to be a positive number"),
+            },
+            None => 2.0,
+        };
+        let millis = |name: &str, what: &str, default: u64| match matches.opt_str(name) {
+            Some(v) => match v.parse::<u64>() {
+                Ok(v) if v > 0 => Duration::from_millis(v),
+                _ => panic!("{} is supposed to be a positive integer", what),
+            },
+            None => Duration::from_millis(default),
+        };
+        let min = millis("adaptive-timeout-min", "Adaptive timeout min", 100);
+        let max = millis("adaptive-timeout-max", "Adaptive timeout max", 30_000);
+        if min > max {
+            panic!("Adaptive timeout min is supposed to be at most the adaptive timeout max");
+        }
+        let window = match matches.opt_str("adaptive-timeout-window") {
+            Some(v) => match v.parse::<u64>() {
+                Ok(v) if v > 0 => Duration::from_secs(v),
+                _ => panic!("Adaptive timeout window is supposed to be a positive integer"),
+            },
+            None => Duration::from_secs(60),
+        };
+        Some((
+            Arc::new(adaptive::AdaptiveTimeout::new(multiplier, min, max)),
+            window,
+        ))
+    } else {
+        None
+    };
+
+    let percent = |name: &str, what: &str| {
+        matches.opt_str(name).map(|v| match v.parse::<f64>() {
+            Ok(v) if (0.0..=100.0).contains(&v) => v,
+            _ => panic!("{} is supposed to be a percentage", what),
+        })
+    };
+    let error_rate_breaker = match (
+        percent("upstream-error-threshold-warn", "Upstream error threshold warn"),
+        percent("upstream-error-threshold-open", "Upstream error threshold open"),
+    ) {
+        (Some(warn), Some(open)) => {
+            if warn >= open {
+                panic!("Upstream error threshold warn is supposed to be below the open threshold");
+            }
+            let window = match matches.opt_str("upstream-error-threshold-window") {
+                Some(v) => match v.parse::<u64>() {
+                    Ok(v) if v > 0 => Duration::from_secs(v),
+                    _ => panic!("Upstream error threshold window is supposed to be a positive integer"),
+                },
+                None => Duration::from_secs(10),
+            };
+            Some((Arc::new(breaker::ErrorRateBreaker::new(warn, open)), window))
+        }
+        (None, None) => None,
+        _ => panic!("You must provide both --upstream-error-threshold-warn and --upstream-error-threshold-open"),
+    };
+
+    let upstream_max_response_size = match matches.opt_str("upstream-max-response-size") {
+        Some(v) => match v.parse::<u64>() {
+            Ok(v) => Some(v),
+            Err(_) => panic!("Upstream max response size is supposed to be a non-negative integer"),
+        },
+        None => None,
+    };
+
+    let redirects = if matches.opt_present("upstream-follow-redirects") {
+        let max = match matches.opt_str("upstream-max-redirects") {
+            Some(v) => match v.parse::<usize>() {
+                Ok(v) => v,
+                Err(_) => panic!("Upstream max redirects is supposed to be a non-negative integer"),
+            },
+            None => 5,
+        };
+        Some(redirect::RedirectPolicy {
+            max,
+            cross_origin: matches.opt_present("upstream-follow-cross-origin-redirects"),
+            method_change: match matches.opt_str("redirect-method-change-policy") {
+                Some(v) => match v.parse::<redirect::MethodChange>() {
+                    Ok(policy) => policy,
+                    Err(e) => panic!("{}", e),
+                },
+                None => redirect::MethodChange::Rfc,
+            },
+        })
+    } else {
+        None
+    };
+
+    let trusted_proxy_count = match matches.opt_str("trusted-proxy-count") {
+        Some(v) => match v.parse::<usize>() {
+            Ok(v) => v,
+            Err(_) => panic!("Trusted proxy count is supposed to be a non-negative integer"),
+        },
+        None => 0,
+    };
+
+    let deadline_header = if matches.opt_present("upstream-deadline-propagation") {
+        let name = matches
+            .opt_str("deadline-header")
+            .unwrap_or_else(|| "X-Request-Deadline".to_string());
+        match HeaderName::from_bytes(name.as_bytes()) {
+            Ok(name) => Some(name),
+            Err(_) => panic!("The deadline header is not a valid header name"),
+        }
+    } else {
+        None
+    };
+
+    let correlation_id_header = match matches.opt_str("log-correlation-id-header") {
+        Some(v) => match HeaderName::from_bytes(v.as_bytes()) {
+            Ok(name) => Some(name),
+            Err(_) => panic!("The correlation ID header is not a valid header name"),
+        },
+        // the request ID options imply the usual header if no other was chosen
+        None if matches.opt_present("request-id-propagate")
+            || matches.opt_present("request-id-always-generate")
+            || matches.opt_present("upstream-no-send-request-id") =>
+        {
+            Some(HeaderName::from_static("x-request-id"))
+        }
+        None => None,
+    };
+    let request_id_always_generate = matches.opt_present("request-id-always-generate");
+    if request_id_always_generate && matches.opt_present("request-id-propagate") {
+        panic!("You cannot specify both --request-id-propagate and --request-id-always-generate");
+    }
+
+    let tap = match matches.opt_str("tap-socket") {
+        Some(path) => {
+            let sample_rate = match matches.opt_str("tap-sample-rate") {
+                Some(v) => match v.parse::<u32>() {
+                    Ok(v) if v <= 100 => v,
+                    _ => panic!("Tap sample rate is supposed to be an integer from 0 to 100"),
+                },
+                None => 100,
+            };
+            let redact = !matches.opt_present("tap-unredacted");
+            match tap::Tap::bind(path.as_str(), sample_rate, redact) {
+                Ok(tap) => Some(tap),
+                Err(e) => panic!("Could not listen on tap socket {}: {}", path, e),
+            }
+        }
+        None => None,
+    };
+
+    let concurrency = limit::ConcurrencyLimit::new(max_concurrent_requests);
+    let mut metrics = metrics::Metrics::new();
+    if let Some(namespace) = matches.opt_str("prometheus-namespace") {
+        if let Err(e) = metrics.set_namespace(namespace.as_str()) {
+            panic!("{}", e);
+        }
+    }
+    let mut metric_labels = Vec::new();
+    for spec in matches.opt_strs("prometheus-labels") {
+        match metrics::parse_labels(spec.as_str()) {
+            Ok(labels) => metric_labels.extend(labels),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    metrics.set_labels(&metric_labels);
+    let backend_labels = if matches.opt_present("upstream-prometheus-labels")
+        || matches.opt_present("backend-label")
+    {
+        if metric_labels.iter().any(|(key, _)| key == "backend") {
+            panic!("The backend label cannot also be given with --prometheus-labels");
+        }
+        let mut labels = HashMap::new();
+        for spec in matches.opt_strs("backend-label") {
+            match metrics::parse_backend_label(spec.as_str()) {
+                Ok((url, label)) => labels.insert(url, label),
+                Err(e) => panic!("{}", e),
+            };
+        }
+        Some(labels)
+    } else {
+        None
+    };
+    {
+        let concurrency = concurrency.clone();
+        metrics.add_gauge(
+            "proxy_concurrent_requests",
+            "The number of requests currently being proxied.",
+            move || concurrency.in_use() as f64,
+        );
+    }
+    let retry_budget = match matches.opt_str("upstream-retry-budget") {
+        Some(v) => match v.parse::<usize>() {
+            Ok(v) => v,
+            Err(_) => panic!("Upstream retry budget is supposed to be a non-negative integer"),
+        },
+        None => 20,
+    };
+    let retry_budget = Arc::new(retry::RetryBudget::new(retry_budget));
+    {
+        let retry_budget = retry_budget.clone();
+        metrics.add_gauge(
+            "proxy_retry_budget_utilization",
+            "The fraction of this second's retry budget that has been used.",
+            move || retry_budget.utilization(),
+        );
+    }
+    let metrics = Arc::new(metrics);
+    let metrics_push = match matches.opt_str("metrics-push-gateway") {
+        Some(gateway) => {
+            if Uri::from_str(gateway.as_str()).is_err() {
+                panic!("The metrics push gateway URL {} is not valid", gateway);
+            }
+            let interval = match matches.opt_str("metrics-push-interval") {
+                Some(v) => match v.parse::<u64>() {
+                    Ok(v) if v > 0 => v,
+                    _ => panic!("Metrics push interval is supposed to be a positive integer"),
+                },
+                None => 15,
+            };
+            let job = matches
+                .opt_str("metrics-job-name")
+                .unwrap_or_else(|| "sidecar-http-proxy".to_string());
+            // not the upstream client, whose HTTP/2 only and TLS options are not for this
+            let push_connector = connector::Connector::new(
+                resolver.clone(),
+                Arc::new(RwLock::new(Arc::new(tls::default_client_config()))),
+            );
+            let push = metrics::push_every(
+                Client::builder().build(push_connector),
+                metrics.clone(),
+                gateway,
+                job,
+                Duration::from_secs(interval),
+            );
+            Some(push)
+        }
+        None => None,
+    };
+
+    let request_timing =
+        if matches.opt_present("log-request-timing") || matches.opt_present("add-timing-headers") {
+            Some(Arc::new(timing::RequestTiming {
+                log: matches.opt_present("log-request-timing"),
+                headers: matches.opt_present("add-timing-headers"),
+            }))
+        } else {
+            None
+        };
+    let settings = Arc::new(Settings {
+        source_match: format!("/{}", source),
+        source_prefix: format!("/{}/", source),
+        balancer: balancer::Balancer::new(backends, strategy),
+        config: config.clone(),
+        failover,
+        connect_retries,
+        decompress_brotli: matches.opt_present("decompress-brotli"),
+        jwt_claim_headers,
+        concurrency,
+        metrics: metrics.clone(),
+        backend_labels,
+        metrics_path: matches.opt_str("metrics-path"),
+        access_log: matches
+            .opt_str("access-log-format")
+            .map(|format| access_log::AccessLogFormat::parse(format.as_str())),
+        access_log_exclude: matches
+            .opt_strs("access-log-exclude-path")
+            .iter()
+            .map(|glob| glob::Glob::new(glob))
+            .chain(matches.opt_str("metrics-path").map(|path| glob::Glob::new(&path)))
+            .collect(),
+        tap,
+        websocket_subprotocols: matches.opt_strs("upstream-ws-subprotocol"),
+        websocket_max_frame_size: matches.opt_str("websocket-max-frame-size").map(|v| {
+            match v.parse::<u64>() {
+                Ok(v) if v > 0 => v,
+                _ => panic!("WebSocket max frame size is supposed to be a positive integer"),
+            }
+        }),
+        retry_budget: retry_budget.clone(),
+        retry_backoff: match matches.opt_str("upstream-retry-backoff") {
+            Some(v) => match v.parse::<retry::BackoffStrategy>() {
+                Ok(strategy) => strategy,
+                Err(e) => panic!("{}", e),
+            },
+            None => retry::BackoffStrategy::default(),
+        },
+        trusted_proxy_count,
+        upstream_header_timeout,
+        adaptive_timeout: adaptive_timeout.as_ref().map(|(t, _)| t.clone()),
+        error_rate_breaker: error_rate_breaker.as_ref().map(|(b, _)| b.clone()),
+        upstream_grpc_timeout: matches.opt_present("upstream-grpc-timeout"),
+        upstream_grpc_web: matches.opt_present("upstream-grpc-web"),
+        upstream_grpc_streaming,
+        upstream_grpc_max_timeout: match matches.opt_str("upstream-grpc-max-timeout") {
+            // grpc-timeout allows at most 8 digits
+            Some(v) => match v.parse::<u64>() {
+                Ok(v) if v > 0 && v < 100_000_000 => Some(Duration::from_millis(v)),
+                _ => panic!("Upstream gRPC max timeout is supposed to be a positive integer below 100000000"),
+            },
+            None => None,
+        },
+        upstream_max_response_size,
+        upstream_error_body_passthrough: matches.opt_present("upstream-error-body-passthrough"),
+        upstream_grpc_status_passthrough: matches.opt_present("upstream-grpc-status-passthrough"),
+        cookie_rewrite,
+        max_age_cap: matches.opt_str("response-header-max-age-cap").map(|v| match v.parse::<u64>() {
+            Ok(v) => v,
+            _ => panic!("Response header max age cap is supposed to be a non-negative integer"),
+        }),
+        redirects,
+        json_rewrites: Arc::new(json_rewrites),
+        json_envelope,
+        script_tag: matches
+            .opt_str("response-inject-script-tag")
+            .map(|url| Arc::new(html::ScriptTag::new(url.as_str()))),
+        request_signer,
+        request_transform,
+        request_compression,
+        fake_upstream,
+        proxy_protocol_clients,
+        preserve_host,
+        rewrite_host,
+        upstream_authority,
+        upstream_authorization,
+        request_timestamp: matches.opt_str("add-request-header-timestamp").map(|name| {
+            let format = matches
+                .opt_str("request-timestamp-format")
+                .unwrap_or_else(|| headers::RequestTimestamp::DEFAULT_FORMAT.to_string());
+            match headers::RequestTimestamp::new(name.as_str(), format.as_str()) {
+                Ok(timestamp) => timestamp,
+                Err(e) => panic!("{}", e),
+            }
+        }),
+        request_body_max_bytes,
+        request_buffer_max_bytes,
+        static_dirs,
+        client: clients,
+        correlation_id_header,
+        request_id_always_generate,
+        upstream_send_request_id: !matches.opt_present("upstream-no-send-request-id"),
+        deadline_header,
+        retry_methods,
+        hedge_after: matches.opt_str("upstream-hedge-after").map(|v| match v.parse::<u64>() {
+            Ok(v) if v > 0 => Duration::from_millis(v),
+            _ => panic!("Upstream hedge after is supposed to be a positive integer"),
+        }),
+        hedge_max: match matches.opt_str("upstream-max-parallel-retries") {
+            Some(v) => match v.parse::<u32>() {
+                Ok(v) if v > 0 => v,
+                _ => panic!("Upstream max parallel retries is supposed to be a positive integer"),
+            },
+            None => 1,
+        },
+        error_response_format: matches.opt_str("error-response-format").map(|v| {
+            match v.parse::<errors::ErrorFormat>() {
+                Ok(format) => format,
+                Err(e) => panic!("{}", e),
+            }
+        }),
+        request_timing,
+    });
+
+    // failover, hedging and connect retries all take their retries from the budget
+    let retry_budget_used = !settings.failover.is_empty()
+        || settings.hedge_after.is_some()
+        || settings.connect_retries > 0;
 
     // A `Service` is needed for every connection.
     let make_svc = make_service_fn(move |socket: &AddrStream| {
-        let target_url = target.clone();
-        let source_match = format!("/{}", source.clone());
-        let source_prefix = format!("/{}/", source.clone());
         let remote_addr = socket.remote_addr();
-        let cache_control = match &cache {
-            Some(v) => Some(v.clone()),
-            _ => None,
-        };
-        service_fn(move |mut req: Request<Body>| {
-            // returns BoxFut
-            if req.uri().path().starts_with(source_prefix.as_str()) {
-                let request_uri = format!("{}", req.uri());
-                let forward_uri = match req.uri().query() {
-                    Some(query) => format!(
-                        "{}?{}",
-                        req.uri().path().replace(source_prefix.as_str(), "/"),
-                        query
-                    ),
-                    None => format!("{}", req.uri().path().replace(source_prefix.as_str(), "/")),
-                };
-                println!(
-                    "[{}] {} Proxy {}{}",
-                    Utc::now(),
-                    request_uri,
-                    target_url,
-                    forward_uri
-                );
-                *req.uri_mut() = Uri::from_str(forward_uri.as_str()).unwrap();
-                let future = hyper_reverse_proxy::call(remote_addr.ip(), target_url.as_str(), req);
-                match &cache_control {
-                    Some(value) => {
-                        let header_value = HeaderValue::from_str(value.clone().as_str()).unwrap();
-                        Box::new(future.map(|mut r| {
-                            if !r.headers().contains_key("Cache-Control") {
-                                let mut headers = HeaderMap::new();
-                                for (k, v) in r.headers().iter() {
-                                    headers.insert(k.clone(), v.clone());
-                                }
-                                headers.insert("Cache-Control", header_value);
-                                *r.headers_mut() = headers;
-                            }
-                            r
-                        }))
-                    }
-                    _ => future,
+        let settings = settings.clone();
+        // one counter per connection
+        let served = AtomicUsize::new(0);
+        service_fn(move |req: Request<Body>| {
+            let http2 = req.version() == Version::HTTP_2;
+            let metrics = settings.metrics.clone();
+            let excluded = settings
+                .access_log_exclude
+                .iter()
+                .any(|glob| glob.matches(req.uri().path()));
+            let pending = settings
+                .access_log
+                .as_ref()
+                .filter(|_| !excluded)
+                .map(|format| format.begin(remote_addr.ip(), &req));
+            let access_settings = settings.clone();
+            let future: BoxFut = Box::new(handle(&settings, remote_addr, req).map(move |r| {
+                metrics.record_response(&r);
+                if let (Some(format), Some(pending)) = (&access_settings.access_log, pending) {
+                    log!("{}", format.render(&pending, &r));
                 }
-            } else if req.uri().path().eq(source_match.as_str()) {
-                println!(
-                    "[{}] {} HTTP/301 Location: {}",
-                    Utc::now(),
-                    req.uri(),
-                    source_prefix
-                );
-                redirect(source_prefix.as_str())
+                r
+            }));
+            let count = served.fetch_add(1, Ordering::SeqCst) + 1;
+            let future = if keepalive_requests > 0 && count >= keepalive_requests {
+                // ask the client to reconnect so that it gets rebalanced
+                Box::new(future.map(|mut r| {
+                    r.headers_mut()
+                        .insert(CONNECTION, HeaderValue::from_static("close"));
+                    r
+                }))
             } else {
-                if req.headers().contains_key("X-Proxy-Debug") {
-                    println!("[{}] {} Debug {:?}", Utc::now(), req.uri(), req);
-                    debug_request(req)
+                future
+            };
+            let future: Box<dyn Future<Item = _, Error = Box<dyn Error + Send + Sync>> + Send> =
+                if http2 {
+                    Box::new(future.from_err().and_then(limit::refuse_stream))
                 } else {
-                    println!("[{}] {} HTTP/404", Utc::now(), req.uri());
-                    not_found()
-                }
-            }
+                    Box::new(future.from_err())
+                };
+            future
         })
     });
 
-    let server = Server::bind(&addr)
+    let mut server = Server::bind(&addr);
+    if let Some(v) = max_header_size(&matches, "client-max-header-size") {
+        server = server.http1_max_buf_size(v);
+    }
+    let max_concurrent_streams = match matches.opt_str("client-max-concurrent-streams") {
+        Some(v) => match v.parse::<u32>() {
+            Ok(v) if v > 0 => v,
+            _ => panic!("Client max concurrent streams is supposed to be a positive integer"),
+        },
+        None => 100,
+    };
+    server = server.http2_max_concurrent_streams(max_concurrent_streams);
+    if matches.opt_present("client-tcp-nodelay") {
+        // hyper flushes after every chunk, so only Nagle's algorithm could hold data back
+        server = server.tcp_nodelay(true);
+    }
+    server
+        .http2_initial_stream_window_size(h2_window(&matches, "client-h2-initial-stream-window"))
+        .http2_initial_connection_window_size(h2_window(
+            &matches,
+            "client-h2-initial-connection-window",
+        ));
+    let server = server
         .serve(make_svc)
         .map_err(|e| eprintln!("server error: {}", e));
 
-    println!("Running server on {:?}", addr);
+    log!("Running server on {:?}", addr);
 
     // Run this server for... forever!
-    hyper::rt::run(server);
+    hyper::rt::run(future::lazy(move || {
+        if let Some(interval) = cert_reload_interval {
+            hyper::rt::spawn(tls::reload_every(upstream_tls, interval, client_config));
+        }
+        if retry_budget_used {
+            hyper::rt::spawn(retry::reset_every_second(retry_budget));
+        }
+        if let Some(push) = metrics_push {
+            hyper::rt::spawn(push);
+        }
+        if let Some((timeout, window)) = adaptive_timeout {
+            hyper::rt::spawn(adaptive::adjust_every(timeout, window));
+        }
+        if let Some((breaker, window)) = error_rate_breaker {
+            hyper::rt::spawn(breaker::update_every(breaker, window));
+        }
+        if let Some(renewal) = client_renewal {
+            hyper::rt::spawn(renewal);
+        }
+        if let Some(path) = config_file {
+            hyper::rt::spawn(config::reload_on_sighup(
+                config,
+                create_options(),
+                args[1..].to_vec(),
+                path,
+            ));
+        }
+        server
+    }));
 }
@@ -0,0 +1,262 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use futures::future::{self, Future};
+use futures::Stream;
+use hyper::{Body, Method, Request, Response};
+use tokio_timer::Interval;
+
+use crate::proxy::HttpClient;
+
+/// The `Content-Type` of the Prometheus text exposition format.
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+type GaugeFn = Box<dyn Fn() -> f64 + Send + Sync>;
+
+/// The metrics we export in the Prometheus text format, either on scrape or by pushing.
+#[derive(Default)]
+pub struct Metrics {
+    /// Keyed by the backend label, if any, and the status code.
+    requests: Mutex<BTreeMap<(Option<String>, u16), u64>>,
+    gauges: Vec<(&'static str, &'static str, GaugeFn)>,
+    namespace: String,
+    labels: String,
+}
+
+fn is_name(name: &str, extra: char) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == extra)
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == extra)
+}
+
+/// The value of the `backend` label for a response, carried in its extensions.
+#[derive(Clone)]
+pub struct BackendLabel(pub String);
+
+/// Parses a `URL:LABEL` backend alias.
+pub fn parse_backend_label(spec: &str) -> Result<(String, String), String> {
+    let mut parts = spec.rsplitn(2, ':');
+    let label = parts.next().unwrap_or("").trim();
+    let url = parts.next().unwrap_or("").trim().trim_end_matches('/');
+    if url.is_empty() || label.is_empty() || label.contains('/') {
+        return Err(format!("Expected URL:LABEL but got '{}'", spec));
+    }
+    Ok((url.to_string(), label.to_string()))
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Parses `KEY=VALUE` label pairs, separated by commas.
+pub fn parse_labels(spec: &str) -> Result<Vec<(String, String)>, String> {
+    let mut labels = Vec::new();
+    for pair in spec.split(',').filter(|p| !p.trim().is_empty()) {
+        let mut parts = pair.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim();
+        let value = match parts.next() {
+            Some(value) => value.trim(),
+            None => return Err(format!("Expected KEY=VALUE but got '{}'", pair)),
+        };
+        if !is_name(key, '_') || key.starts_with("__") || key == "code" {
+            return Err(format!("'{}' is not a usable Prometheus label name", key));
+        }
+        labels.push((key.to_string(), value.to_string()));
+    }
+    Ok(labels)
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Prefixes every metric name with `namespace_`.
+    pub fn set_namespace(&mut self, namespace: &str) -> Result<(), String> {
+        if !is_name(namespace, ':') {
+            return Err(format!(
+                "'{}' is not a valid Prometheus metric name prefix",
+                namespace
+            ));
+        }
+        self.namespace = format!("{}_", namespace);
+        Ok(())
+    }
+
+    /// Adds constant labels to every metric, e.g. to tell apart the instances of the proxy.
+    pub fn set_labels(&mut self, labels: &[(String, String)]) {
+        self.labels = labels
+            .iter()
+            .map(|(key, value)| format!("{}=\"{}\"", key, escape(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+    }
+
+    /// Adds a gauge whose value is read from `value` whenever the metrics are rendered.
+    pub fn add_gauge<F>(&mut self, name: &'static str, help: &'static str, value: F)
+    where
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        self.gauges.push((name, help, Box::new(value)));
+    }
+
+    /// Counts a response returned to a client, by the backend if it has a `BackendLabel`.
+    pub fn record_response<B>(&self, response: &Response<B>) {
+        let backend = response
+            .extensions()
+            .get::<BackendLabel>()
+            .map(|label| label.0.clone());
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((backend, response.status().as_u16()))
+            .or_insert(0) += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let ns = self.namespace.as_str();
+        let (labels, sep) = match self.labels.as_str() {
+            "" => ("", ""),
+            labels => (labels, ","),
+        };
+        writeln!(
+            out,
+            "# HELP {}proxy_requests_total The number of responses returned to clients.",
+            ns
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {}proxy_requests_total counter", ns).unwrap();
+        for ((backend, code), count) in self.requests.lock().unwrap().iter() {
+            let backend = match backend {
+                Some(backend) => format!("backend=\"{}\",", escape(backend)),
+                None => String::new(),
+            };
+            writeln!(
+                out,
+                "{}proxy_requests_total{{{}{}{}code=\"{}\"}} {}",
+                ns, labels, sep, backend, code, count
+            )
+            .unwrap();
+        }
+        for (name, help, value) in &self.gauges {
+            writeln!(out, "# HELP {}{} {}", ns, name, help).unwrap();
+            writeln!(out, "# TYPE {}{} gauge", ns, name).unwrap();
+            if labels.is_empty() {
+                writeln!(out, "{}{} {}", ns, name, value()).unwrap();
+            } else {
+                writeln!(out, "{}{}{{{}}} {}", ns, name, labels, value()).unwrap();
+            }
+        }
+        out
+    }
+
+    pub fn response(&self) -> Response<Body> {
+        Response::builder()
+            .header("Content-Type", CONTENT_TYPE)
+            .body(Body::from(self.render()))
+            .unwrap()
+    }
+}
+
+/// Pushes the metrics to a Prometheus Pushgateway every `interval`.
+///
+/// A failed push is logged and then simply retried on the next tick.
+pub fn push_every(
+    client: HttpClient,
+    metrics: Arc<Metrics>,
+    gateway: String,
+    job: String,
+    interval: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    let url = format!("{}/metrics/job/{}", gateway.trim_end_matches('/'), job);
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(|e| log_error!("metrics push timer error: {}", e))
+        .for_each(move |_| {
+            let request = Request::builder()
+                .method(Method::PUT)
+                .uri(url.as_str())
+                .header("Content-Type", CONTENT_TYPE)
+                .body(Body::from(metrics.render()));
+            let request = match request {
+                Ok(request) => request,
+                Err(e) => {
+                    log_error!(
+                        "[{}] WARN Could not push metrics to {}: {}",
+                        Utc::now(),
+                        url,
+                        e
+                    );
+                    return future::Either::A(future::ok(()));
+                }
+            };
+            let url = url.clone();
+            future::Either::B(client.request(request).then(move |response| {
+                match response {
+                    Ok(ref r) if r.status().is_success() => {}
+                    Ok(r) => log_error!(
+                        "[{}] WARN Could not push metrics to {}: HTTP/{}",
+                        Utc::now(),
+                        url,
+                        r.status().as_u16()
+                    ),
+                    Err(e) => {
+                        log_error!(
+                            "[{}] WARN Could not push metrics to {}: {}",
+                            Utc::now(),
+                            url,
+                            e
+                        )
+                    }
+                }
+                Ok(())
+            }))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_label_pairs() {
+        assert_eq!(
+            parse_labels("region = eu-west-1, zone=a,").unwrap(),
+            vec![
+                ("region".to_string(), "eu-west-1".to_string()),
+                ("zone".to_string(), "a".to_string()),
+            ]
+        );
+        assert!(parse_labels("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_pairs_without_a_value() {
+        assert!(parse_labels("region").is_err());
+    }
+
+    #[test]
+    fn rejects_unusable_label_names() {
+        assert!(parse_labels("1region=eu").is_err());
+        assert!(parse_labels("__name__=x").is_err());
+        assert!(parse_labels("code=200").is_err());
+        assert!(parse_labels("my-label=x").is_err());
+    }
+}
@@ -0,0 +1,444 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::error::Error;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Future};
+use futures::{stream, try_ready, Async, Poll, Stream};
+use hyper::header::{
+    HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING,
+};
+use hyper::http::request::Parts;
+use hyper::{Body, Chunk, Client, Method, Request, Response, StatusCode, Uri};
+use tokio_timer::timeout::{self, Timeout};
+use tokio_timer::Interval;
+
+use crate::config::RouteTimeout;
+use crate::connector::{Connector, ProxyProtocol};
+use crate::errors::{ErrorFormat, ErrorStyle};
+use crate::forwarded::ClientIp;
+use crate::grpc;
+use crate::logging::RequestTag;
+use crate::websocket::Handshake;
+use crate::BoxFut;
+
+pub type HttpClient = Client<Connector, Body>;
+
+/// Makes clients whose upstream connections start with a PROXY protocol header.
+///
+/// The header describes a single client, so these connections are made per request and
+/// never pooled.
+pub struct ProxyProtocolClients {
+    pub version: ProxyProtocol,
+    pub connector: Connector,
+    pub builder: hyper::client::Builder,
+}
+
+impl ProxyProtocolClients {
+    pub fn client(&self, source: SocketAddr) -> HttpClient {
+        self.builder
+            .build(self.connector.with_proxy_header(self.version, source))
+    }
+}
+
+/// Replaces the client every `lifetime`, so that its pooled connections are no longer reused.
+///
+/// The old client's connections close once their in-flight requests are done, so none are
+/// used for much longer than `lifetime`.
+pub fn renew_every(
+    current: Arc<RwLock<HttpClient>>,
+    builder: hyper::client::Builder,
+    connector: Connector,
+    lifetime: Duration,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now() + lifetime, lifetime)
+        .map_err(|e| log_error!("connection pool renewal timer error: {}", e))
+        .for_each(move |_| {
+            *current.write().unwrap() = builder.build(connector.clone());
+            Ok(())
+        })
+}
+
+/// The [hop-by-hop headers] which must not be forwarded by a proxy.
+///
+/// [hop-by-hop headers]: http://www.w3.org/Protocols/rfc2616/rfc2616-sec13.html
+const HOP_HEADERS: [&str; 8] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Te",
+    "Trailers",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+fn is_hop_header(name: &str) -> bool {
+    HOP_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name))
+}
+
+/// Returns a clone of the headers without the hop-by-hop headers.
+fn remove_hop_headers(headers: &HeaderMap<HeaderValue>) -> HeaderMap<HeaderValue> {
+    let mut result = HeaderMap::new();
+    for (k, v) in headers.iter() {
+        if !is_hop_header(k.as_str()) {
+            result.append(k.clone(), v.clone());
+        }
+    }
+    result
+}
+
+fn create_proxied_response<B>(mut response: Response<B>) -> Response<B> {
+    *response.headers_mut() = remove_hop_headers(response.headers());
+    response
+}
+
+fn forward_uri<B>(forward_url: &str, req: &Request<B>) -> Uri {
+    let forward_uri = match req.uri().query() {
+        Some(query) => format!("{}{}?{}", forward_url, req.uri().path(), query),
+        None => format!("{}{}", forward_url, req.uri().path()),
+    };
+
+    Uri::from_str(forward_uri.as_str()).unwrap()
+}
+
+fn create_proxied_request<B>(
+    client_ip: IpAddr,
+    forward_url: &str,
+    mut request: Request<B>,
+) -> Request<B> {
+    *request.headers_mut() = remove_hop_headers(request.headers());
+    if Handshake::of(&request).is_some() {
+        Handshake::request_upgrade(request.headers_mut());
+    }
+    *request.uri_mut() = forward_uri(forward_url, &request);
+
+    // Add forwarding information in the headers
+    let forwarded_for = match request.headers().get("X-Forwarded-For") {
+        Some(existing) => format!("{}, {}", existing.to_str().unwrap_or(""), client_ip),
+        None => format!("{}", client_ip),
+    };
+    request.headers_mut().insert(
+        "X-Forwarded-For",
+        HeaderValue::from_str(forwarded_for.as_str()).unwrap(),
+    );
+
+    request
+}
+
+/// Marks a response made up because we could not connect to the upstream at all, so the
+/// request never reached it and is always safe to retry.
+#[derive(Clone, Copy)]
+pub struct ConnectFailed;
+
+/// Marks a response made up because the upstream did not send the response headers within
+/// the header timeout.
+#[derive(Clone, Copy)]
+pub struct TimedOut;
+
+/// How long to wait for the upstream to send the response headers.
+#[derive(Clone, Copy)]
+pub enum HeaderTimeout {
+    /// Answered with a 504 when it runs out.
+    Http(Duration),
+    /// A gRPC deadline, answered with a gRPC DEADLINE_EXCEEDED status when it runs out.
+    Grpc(Duration),
+}
+
+impl HeaderTimeout {
+    pub fn duration(self) -> Duration {
+        match self {
+            HeaderTimeout::Http(duration) | HeaderTimeout::Grpc(duration) => duration,
+        }
+    }
+}
+
+/// Forwards the request to `forward_url` using the supplied client.
+///
+/// If the upstream has not sent the response headers within `header_timeout` the client
+/// gets a 504 instead.
+pub fn call(
+    client: &HttpClient,
+    client_ip: IpAddr,
+    forward_url: &str,
+    request: Request<Body>,
+    header_timeout: Option<HeaderTimeout>,
+) -> BoxFut {
+    let tag = RequestTag::of(&request);
+    let style = ErrorStyle::of(&request);
+    let handshake = Handshake::of(&request);
+    let proxied_request = create_proxied_request(client_ip, forward_url, request);
+
+    let response = client.request(proxied_request);
+    let response = match header_timeout {
+        Some(timeout) => future::Either::A(Timeout::new(response, timeout.duration())),
+        None => future::Either::B(response.map_err(timeout::Error::inner)),
+    };
+    let response = response.then(move |response| {
+        let proxied_response = match response {
+            Ok(response) => match handshake {
+                Some(handshake) => handshake.complete(create_proxied_response(response), tag),
+                None => create_proxied_response(response),
+            },
+            Err(ref error) if error.is_elapsed() => {
+                log_error!("{}Error: timed out waiting for the response headers", tag);
+                let mut response = match header_timeout {
+                    Some(HeaderTimeout::Grpc(_)) => grpc::deadline_exceeded(),
+                    _ => style.response(
+                        StatusCode::GATEWAY_TIMEOUT,
+                        "timed out waiting for the upstream response",
+                        ErrorFormat::Text,
+                    ),
+                };
+                response.extensions_mut().insert(TimedOut);
+                response
+            }
+            Err(error) => match error.into_inner() {
+                Some(error) => {
+                    log_error!("{}Error: {}", tag, error);
+                    let (status, message) = classify(&error);
+                    let mut response = style.response(status, message.as_str(), ErrorFormat::Text);
+                    if error.is_connect() {
+                        response.extensions_mut().insert(ConnectFailed);
+                    }
+                    response
+                }
+                None => {
+                    log_error!("{}Error: response header timer failed", tag);
+                    style.response(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "internal error",
+                        ErrorFormat::Text,
+                    )
+                }
+            },
+        };
+        future::ok(proxied_response)
+    });
+
+    Box::new(response)
+}
+
+/// Picks the status code to return to the client when the upstream request failed.
+fn classify(error: &hyper::Error) -> (StatusCode, String) {
+    let timed_out = error
+        .source()
+        .and_then(|e| e.downcast_ref::<io::Error>())
+        .is_some_and(|e| e.kind() == io::ErrorKind::TimedOut);
+    if timed_out {
+        (
+            StatusCode::GATEWAY_TIMEOUT,
+            "timed out connecting to the upstream".to_string(),
+        )
+    } else if error.is_connect() {
+        (
+            StatusCode::BAD_GATEWAY,
+            "could not connect to the upstream".to_string(),
+        )
+    } else if error.is_parse() {
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("invalid response from the upstream: {}", error),
+        )
+    } else if error.is_user() {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal error".to_string(),
+        )
+    } else {
+        (
+            StatusCode::BAD_GATEWAY,
+            "the upstream connection failed".to_string(),
+        )
+    }
+}
+
+/// A request whose body has been buffered so that it can be sent more than once.
+pub struct ReplayableRequest {
+    parts: Parts,
+    body: Vec<u8>,
+}
+
+/// A request after trying to buffer its body for replay.
+pub enum Buffered {
+    Replayable(ReplayableRequest),
+    /// The body is too large to keep, so the request can only be sent once, as it is.
+    TooLarge(Request<Body>),
+}
+
+/// Reads a request body into memory until it either ends or passes the limit.
+struct Buffer {
+    parts: Option<Parts>,
+    body: Option<Body>,
+    chunks: Vec<Chunk>,
+    read: u64,
+    limit: u64,
+}
+
+impl Buffer {
+    /// The request with the chunks read so far put back in front of the rest of its body.
+    fn too_large(&mut self) -> Buffered {
+        let chunks = stream::iter_ok::<_, hyper::Error>(self.chunks.split_off(0));
+        let body = chunks.chain(self.body.take().unwrap());
+        Buffered::TooLarge(Request::from_parts(
+            self.parts.take().unwrap(),
+            Body::wrap_stream(body),
+        ))
+    }
+}
+
+impl Future for Buffer {
+    type Item = Buffered;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Buffered, hyper::Error> {
+        loop {
+            match try_ready!(self.body.as_mut().unwrap().poll()) {
+                Some(chunk) => {
+                    self.read += chunk.len() as u64;
+                    self.chunks.push(chunk);
+                    if self.read > self.limit {
+                        return Ok(Async::Ready(self.too_large()));
+                    }
+                }
+                None => {
+                    let mut body = Vec::with_capacity(self.read as usize);
+                    for chunk in &self.chunks {
+                        body.extend_from_slice(chunk);
+                    }
+                    return Ok(Async::Ready(Buffered::Replayable(ReplayableRequest {
+                        parts: self.parts.take().unwrap(),
+                        body,
+                    })));
+                }
+            }
+        }
+    }
+}
+
+impl ReplayableRequest {
+    /// Buffers the body of `request` so that it can be sent more than once, unless it is more
+    /// than `limit` bytes.
+    pub fn buffer(
+        request: Request<Body>,
+        limit: u64,
+    ) -> impl Future<Item = Buffered, Error = hyper::Error> {
+        let content_length = request
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok());
+        if content_length.is_some_and(|length| length > limit) {
+            return future::Either::A(future::ok(Buffered::TooLarge(request)));
+        }
+        let (parts, body) = request.into_parts();
+        future::Either::B(Buffer {
+            parts: Some(parts),
+            body: Some(body),
+            chunks: Vec::new(),
+            read: 0,
+            limit,
+        })
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.parts.method
+    }
+
+    pub fn to_request(&self) -> Request<Body> {
+        let mut request = Request::new(Body::from(self.body.clone()));
+        *request.method_mut() = self.parts.method.clone();
+        *request.uri_mut() = self.parts.uri.clone();
+        *request.version_mut() = self.parts.version;
+        *request.headers_mut() = self.parts.headers.clone();
+        if let Some(tag) = self.parts.extensions.get::<RequestTag>() {
+            request.extensions_mut().insert(tag.clone());
+        }
+        if let Some(client_ip) = self.parts.extensions.get::<ClientIp>() {
+            request.extensions_mut().insert(*client_ip);
+        }
+        if let Some(style) = self.parts.extensions.get::<ErrorStyle>() {
+            request.extensions_mut().insert(style.clone());
+        }
+        if let Some(handshake) = self.parts.extensions.get::<Handshake>() {
+            request.extensions_mut().insert(handshake.clone());
+        }
+        if let Some(timeout) = self.parts.extensions.get::<RouteTimeout>() {
+            request.extensions_mut().insert(*timeout);
+        }
+        request
+    }
+
+    /// The same request as a `GET` without a body, e.g. to follow a 303 redirect.
+    pub fn as_get(&self) -> ReplayableRequest {
+        let (mut parts, _) = self.to_request().into_parts();
+        parts.method = Method::GET;
+        for name in &[
+            CONTENT_LENGTH,
+            CONTENT_TYPE,
+            CONTENT_ENCODING,
+            TRANSFER_ENCODING,
+        ] {
+            parts.headers.remove(name);
+        }
+        ReplayableRequest {
+            parts,
+            body: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(chunks: &[&'static str]) -> Request<Body> {
+        let chunks: Vec<Result<_, hyper::Error>> = chunks.iter().map(|c| Ok(*c)).collect();
+        Request::new(Body::wrap_stream(stream::iter_result(chunks)))
+    }
+
+    #[test]
+    fn buffers_a_body_within_the_limit() {
+        match ReplayableRequest::buffer(chunked(&["abc", "def"]), 6)
+            .wait()
+            .unwrap()
+        {
+            Buffered::Replayable(req) => assert_eq!(req.body, b"abcdef"),
+            Buffered::TooLarge(_) => panic!("expected the body to be buffered"),
+        }
+    }
+
+    #[test]
+    fn keeps_all_of_a_body_past_the_limit() {
+        match ReplayableRequest::buffer(chunked(&["abc", "def", "ghi"]), 4)
+            .wait()
+            .unwrap()
+        {
+            Buffered::Replayable(_) => panic!("expected the body to be too large"),
+            Buffered::TooLarge(req) => {
+                let body = req.into_body().concat2().wait().unwrap();
+                assert_eq!(&body[..], b"abcdefghi");
+            }
+        }
+    }
+
+    #[test]
+    fn does_not_read_a_body_whose_length_is_past_the_limit() {
+        let mut req = Request::new(Body::from("abcdef"));
+        req.headers_mut().insert(CONTENT_LENGTH, 6.into());
+        match ReplayableRequest::buffer(req, 4).wait().unwrap() {
+            Buffered::Replayable(_) => panic!("expected the body to be too large"),
+            Buffered::TooLarge(req) => assert_eq!(req.headers()[CONTENT_LENGTH], "6"),
+        }
+    }
+}
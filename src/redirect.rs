@@ -0,0 +1,116 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::str::FromStr;
+
+use hyper::header::LOCATION;
+use hyper::{Method, Response, StatusCode, Uri};
+
+/// How the proxy follows redirects returned by the upstream.
+pub struct RedirectPolicy {
+    /// How many redirects to follow before giving up with a 502.
+    pub max: usize,
+    /// Whether to follow redirects to a different scheme, host or port.
+    pub cross_origin: bool,
+    /// When a redirect turns the request into a `GET`.
+    pub method_change: MethodChange,
+}
+
+/// When following a redirect turns the request into a `GET` without a body.
+///
+/// A 303 always does (except for `HEAD`), and a 307 or 308 never does. The policies differ
+/// over a 301 or 302 in response to a request other than `GET` or `HEAD`:
+///
+/// * `rfc` re-sends it unchanged, as RFC 7231 allows
+/// * `strict` returns the redirect to the client, as the method to use is ambiguous
+/// * `permissive` turns it into a `GET`, as browsers do
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MethodChange {
+    Rfc,
+    Strict,
+    Permissive,
+}
+
+impl FromStr for MethodChange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MethodChange, String> {
+        match s {
+            "rfc" => Ok(MethodChange::Rfc),
+            "strict" => Ok(MethodChange::Strict),
+            "permissive" => Ok(MethodChange::Permissive),
+            _ => Err(format!(
+                "Redirect method change policy is supposed to be rfc, strict or permissive, not {}",
+                s
+            )),
+        }
+    }
+}
+
+/// How to re-send a request to follow a redirect.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Resend {
+    Unchanged,
+    AsGet,
+}
+
+/// Whether the response is a redirect that we know how to follow.
+pub fn is_redirect(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::SEE_OTHER
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT
+    )
+}
+
+/// How to re-send a `method` request to follow a redirect with `status`, or `None` when the
+/// redirect is to be returned to the client.
+pub fn resend(policy: MethodChange, status: StatusCode, method: &Method) -> Option<Resend> {
+    let safe = method == Method::GET || method == Method::HEAD;
+    match status {
+        StatusCode::SEE_OTHER if method == Method::HEAD => Some(Resend::Unchanged),
+        StatusCode::SEE_OTHER => Some(Resend::AsGet),
+        StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND if !safe => match policy {
+            MethodChange::Rfc => Some(Resend::Unchanged),
+            MethodChange::Strict => None,
+            MethodChange::Permissive => Some(Resend::AsGet),
+        },
+        _ => Some(Resend::Unchanged),
+    }
+}
+
+/// The `scheme://authority` part of an absolute URI.
+pub fn origin(uri: &Uri) -> String {
+    format!(
+        "{}://{}",
+        uri.scheme_str().unwrap_or("http"),
+        uri.authority_part().map(|a| a.as_str()).unwrap_or("")
+    )
+}
+
+/// Works out the absolute URI that a redirect points to, relative to the `current` request.
+pub fn location<B>(response: &Response<B>, current: &Uri) -> Option<Uri> {
+    let location = response.headers().get(LOCATION)?.to_str().ok()?;
+    let absolute = if location.starts_with("http://") || location.starts_with("https://") {
+        location.to_string()
+    } else if location.starts_with("//") {
+        format!("{}:{}", current.scheme_str()?, location)
+    } else if location.starts_with('/') {
+        format!("{}{}", origin(current), location)
+    } else {
+        let path = current.path();
+        let dir = &path[..path.rfind('/').map(|i| i + 1).unwrap_or(0)];
+        format!("{}{}{}", origin(current), dir, location)
+    };
+    let uri = Uri::from_str(absolute.as_str()).ok()?;
+    uri.authority_part()?;
+    Some(uri)
+}
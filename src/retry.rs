@@ -0,0 +1,278 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use hyper::Method;
+use tokio_timer::Interval;
+
+/// Retries that are always allowed in a window, so that quiet periods can still retry.
+const MIN_RETRIES_PER_WINDOW: usize = 10;
+
+/// The request methods that may be sent upstream again, by retries, failover or hedging.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RetryMethods {
+    /// `GET`, `HEAD` and `OPTIONS`.
+    Safe,
+    /// The safe methods plus `PUT` and `DELETE`, which can be repeated to the same effect.
+    Idempotent,
+    /// Any method, even those like `POST` that may then take effect twice.
+    All,
+}
+
+impl RetryMethods {
+    pub fn allows(self, method: &Method) -> bool {
+        match self {
+            RetryMethods::Safe => {
+                method == Method::GET || method == Method::HEAD || method == Method::OPTIONS
+            }
+            RetryMethods::Idempotent => {
+                RetryMethods::Safe.allows(method)
+                    || method == Method::PUT
+                    || method == Method::DELETE
+            }
+            RetryMethods::All => true,
+        }
+    }
+}
+
+/// How long to wait between retries.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackoffStrategy {
+    /// The same delay before every retry.
+    Fixed(Duration),
+    /// A delay starting at `base` and doubling for each retry, up to `max`.
+    Exponential { base: Duration, max: Duration },
+    /// A random delay up to what `Exponential` would wait ("full jitter"), so that clients
+    /// that failed together do not all retry together.
+    ExponentialJitter { base: Duration, max: Duration },
+}
+
+impl Default for BackoffStrategy {
+    fn default() -> BackoffStrategy {
+        BackoffStrategy::Exponential {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+impl BackoffStrategy {
+    /// How long to wait before retry number `attempt`, counting from 0.
+    pub fn next_delay(&self, attempt: u32) -> Duration {
+        match *self {
+            BackoffStrategy::Fixed(delay) => delay,
+            BackoffStrategy::Exponential { base, max } => exponential(base, max, attempt),
+            BackoffStrategy::ExponentialJitter { base, max } => {
+                exponential(base, max, attempt).mul_f64(rand::random::<f64>())
+            }
+        }
+    }
+}
+
+fn exponential(base: Duration, max: Duration, attempt: u32) -> Duration {
+    base.checked_mul(2u32.saturating_pow(attempt))
+        .map_or(max, |delay| delay.min(max))
+}
+
+impl FromStr for BackoffStrategy {
+    type Err = String;
+
+    /// Parses `fixed:MS`, `exponential:BASE_MS:MAX_MS` or `exponential+jitter:BASE_MS:MAX_MS`.
+    fn from_str(s: &str) -> Result<BackoffStrategy, String> {
+        let mut parts = s.split(':');
+        let name = parts.next().unwrap_or("");
+        let millis = parts
+            .map(|v| v.parse::<u64>().map(Duration::from_millis))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| {
+                format!(
+                    "Invalid retry backoff '{}', the delays must be milliseconds",
+                    s
+                )
+            })?;
+        match (name, millis.as_slice()) {
+            ("fixed", &[delay]) => Ok(BackoffStrategy::Fixed(delay)),
+            ("exponential", &[base, max]) if base <= max => {
+                Ok(BackoffStrategy::Exponential { base, max })
+            }
+            ("exponential+jitter", &[base, max]) if base <= max => {
+                Ok(BackoffStrategy::ExponentialJitter { base, max })
+            }
+            ("exponential", &[_, _]) | ("exponential+jitter", &[_, _]) => Err(format!(
+                "Invalid retry backoff '{}', the maximum delay is less than the base",
+                s
+            )),
+            _ => Err(format!(
+                "Unknown retry backoff '{}', expected one of: fixed:MS, exponential:BASE_MS:MAX_MS, exponential+jitter:BASE_MS:MAX_MS",
+                s
+            )),
+        }
+    }
+}
+
+/// Limits retries to a percentage of the original requests seen in the current window.
+pub struct RetryBudget {
+    percent: usize,
+    requests: AtomicUsize,
+    retries: AtomicUsize,
+}
+
+impl RetryBudget {
+    pub fn new(percent: usize) -> RetryBudget {
+        RetryBudget {
+            percent,
+            requests: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+        }
+    }
+
+    fn allowed(&self) -> usize {
+        MIN_RETRIES_PER_WINDOW + self.requests.load(Ordering::SeqCst) * self.percent / 100
+    }
+
+    /// Counts an original (i.e. not retried) request.
+    pub fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Takes a retry from the budget, returning `false` if it has been used up.
+    pub fn try_retry(&self) -> bool {
+        let retries = self.retries.fetch_add(1, Ordering::SeqCst);
+        if retries >= self.allowed() {
+            self.retries.fetch_sub(1, Ordering::SeqCst);
+            return false;
+        }
+        true
+    }
+
+    /// How much of the budget for the current window has been used, from 0 to 1.
+    pub fn utilization(&self) -> f64 {
+        self.retries.load(Ordering::SeqCst) as f64 / self.allowed() as f64
+    }
+
+    fn reset(&self) {
+        self.requests.store(0, Ordering::SeqCst);
+        self.retries.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Starts a new budget window every second.
+pub fn reset_every_second(budget: Arc<RetryBudget>) -> impl Future<Item = (), Error = ()> {
+    let second = Duration::from_secs(1);
+    Interval::new(Instant::now() + second, second)
+        .map_err(|e| log_error!("retry budget timer error: {}", e))
+        .for_each(move |_| {
+            budget.reset();
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_backoff_strategies() {
+        assert_eq!(
+            "fixed:250".parse::<BackoffStrategy>(),
+            Ok(BackoffStrategy::Fixed(Duration::from_millis(250)))
+        );
+        assert_eq!(
+            "exponential:100:5000".parse::<BackoffStrategy>(),
+            Ok(BackoffStrategy::Exponential {
+                base: Duration::from_millis(100),
+                max: Duration::from_millis(5000),
+            })
+        );
+        assert_eq!(
+            "exponential+jitter:100:5000".parse::<BackoffStrategy>(),
+            Ok(BackoffStrategy::ExponentialJitter {
+                base: Duration::from_millis(100),
+                max: Duration::from_millis(5000),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_backoff_strategies() {
+        assert!("fixed".parse::<BackoffStrategy>().is_err());
+        assert!("fixed:soon".parse::<BackoffStrategy>().is_err());
+        assert!("exponential:100".parse::<BackoffStrategy>().is_err());
+        assert!("exponential:5000:100".parse::<BackoffStrategy>().is_err());
+        assert!("linear:100".parse::<BackoffStrategy>().is_err());
+    }
+
+    #[test]
+    fn exponential_backoff_doubles_up_to_the_maximum() {
+        let backoff = BackoffStrategy::default();
+        let delays: Vec<u128> = (0..8).map(|n| backoff.next_delay(n).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1600, 3200, 5000, 5000]);
+        assert_eq!(backoff.next_delay(u32::MAX), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn jittered_backoff_stays_under_the_exponential_delay() {
+        let backoff = BackoffStrategy::ExponentialJitter {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+        };
+        for attempt in 0..10 {
+            let limit = BackoffStrategy::default().next_delay(attempt);
+            assert!(backoff.next_delay(attempt) <= limit);
+        }
+    }
+
+    #[test]
+    fn fixed_backoff_never_changes() {
+        let backoff = BackoffStrategy::Fixed(Duration::from_millis(250));
+        assert_eq!(backoff.next_delay(0), backoff.next_delay(7));
+    }
+
+    #[test]
+    fn retry_methods() {
+        assert!(RetryMethods::Safe.allows(&Method::GET));
+        assert!(!RetryMethods::Safe.allows(&Method::PUT));
+        assert!(RetryMethods::Idempotent.allows(&Method::DELETE));
+        assert!(!RetryMethods::Idempotent.allows(&Method::POST));
+        assert!(RetryMethods::All.allows(&Method::POST));
+    }
+
+    fn exhaust(budget: &RetryBudget) {
+        while budget.try_retry() {}
+    }
+
+    #[test]
+    fn budget_allows_a_percentage_of_requests() {
+        let budget = RetryBudget::new(50);
+        for _ in 0..20 {
+            budget.record_request();
+        }
+        let mut retries = 0;
+        while budget.try_retry() {
+            retries += 1;
+        }
+        assert_eq!(retries, MIN_RETRIES_PER_WINDOW + 10);
+        assert_eq!(budget.utilization(), 1.0);
+    }
+
+    #[test]
+    fn budget_refills_on_reset() {
+        let budget = RetryBudget::new(20);
+        exhaust(&budget);
+        assert!(!budget.try_retry());
+
+        budget.reset();
+        assert!(budget.try_retry());
+        assert_eq!(budget.utilization(), 1.0 / MIN_RETRIES_PER_WINDOW as f64);
+    }
+}
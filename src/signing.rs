@@ -0,0 +1,72 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::Future;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
+use hyper::{Body, Request};
+use ring::{digest, hmac};
+
+use crate::body;
+
+/// Signs upstream requests with `X-Proxy-Signature: t=<TS>,v1=<HMAC>`.
+///
+/// The HMAC-SHA256 is over `<TS>.<METHOD>.<PATH>.<SHA256 of the body>`, all hex encoded.
+pub struct RequestSigner {
+    key: hmac::Key,
+    header: HeaderName,
+    max_body_bytes: u64,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl RequestSigner {
+    pub fn new(secret: &str, header: HeaderName, max_body_bytes: u64) -> RequestSigner {
+        RequestSigner {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+            header,
+            max_body_bytes,
+        }
+    }
+
+    fn signature(&self, timestamp: i64, method: &str, path: &str, body: &[u8]) -> String {
+        let body_hash = hex(digest::digest(&digest::SHA256, body).as_ref());
+        let message = format!("{}.{}.{}.{}", timestamp, method, path, body_hash);
+        let tag = hmac::sign(&self.key, message.as_bytes());
+        format!("t={},v1={}", timestamp, hex(tag.as_ref()))
+    }
+
+    /// Buffers the request body and adds the signature header.
+    ///
+    /// Resolves to `None` if the body is larger than the signing limit.
+    pub fn sign(
+        self: Arc<Self>,
+        req: Request<Body>,
+    ) -> impl Future<Item = Option<Request<Body>>, Error = hyper::Error> {
+        let timestamp = Utc::now().timestamp();
+        body::buffer_request(req, self.max_body_bytes).map(move |buffered| {
+            let (mut parts, body) = buffered?;
+            let path = parts
+                .uri
+                .path_and_query()
+                .map(|p| p.as_str())
+                .unwrap_or("/");
+            let value = self.signature(timestamp, parts.method.as_str(), path, &body);
+            parts.headers.insert(
+                self.header.clone(),
+                HeaderValue::from_str(value.as_str()).unwrap(),
+            );
+            parts.headers.insert(CONTENT_LENGTH, body.len().into());
+            Some(Request::from_parts(parts, Body::from(body)))
+        })
+    }
+}
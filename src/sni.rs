@@ -0,0 +1,239 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::future::{self, Future, Loop};
+use futures::Stream;
+use hyper::client::connect::dns::{Name, Resolve};
+use hyper::Uri;
+use tokio_io::io::{copy, read, shutdown, write_all};
+use tokio_io::AsyncRead;
+use tokio_tcp::{TcpListener, TcpStream};
+
+use crate::dns::CachingResolver;
+use crate::vhost::HostRules;
+
+/// The largest TLS record, which bounds how much we read looking for the ClientHello.
+const MAX_RECORD: usize = 5 + 16384;
+
+/// Where connections for a TLS server name are sent.
+pub struct Route {
+    server_name: String,
+    host: String,
+    port: u16,
+}
+
+impl Route {
+    /// Routes the host of an `https` target URL to that host and port.
+    pub fn from_url(url: &str) -> Result<Route, String> {
+        let uri = Uri::from_str(url).map_err(|_| format!("The target URL {} is not valid", url))?;
+        if uri.scheme_str() != Some("https") {
+            return Err(format!(
+                "SNI passthrough needs https target URLs but got {}",
+                url
+            ));
+        }
+        let host = match uri.host() {
+            Some(host) => host.to_string(),
+            None => return Err(format!("The target URL {} must include a host", url)),
+        };
+        Ok(Route {
+            server_name: host.to_ascii_lowercase(),
+            host,
+            port: uri.port_u16().unwrap_or(443),
+        })
+    }
+}
+
+/// Reads a big-endian integer of `len` bytes, advancing `pos` past it.
+fn read_int(data: &[u8], pos: &mut usize, len: usize) -> Option<usize> {
+    let bytes = data.get(*pos..*pos + len)?;
+    *pos += len;
+    Some(bytes.iter().fold(0, |n, b| (n << 8) | *b as usize))
+}
+
+/// Skips over a block of data prefixed by its length in `len` bytes.
+fn skip_block(data: &[u8], pos: &mut usize, len: usize) -> Option<()> {
+    *pos += read_int(data, pos, len)?;
+    Some(())
+}
+
+/// Finds the server name in a TLS ClientHello, assuming it fits in the first record.
+fn server_name(record: &[u8]) -> Option<String> {
+    // record header: content type 22 (handshake), version, length
+    if record.first() != Some(&22) {
+        return None;
+    }
+    let mut pos = 5;
+    // handshake header: type 1 (ClientHello) and a 3 byte length
+    if record.get(pos) != Some(&1) {
+        return None;
+    }
+    pos += 4;
+    pos += 2 + 32; // client version and random
+    skip_block(record, &mut pos, 1)?; // session id
+    skip_block(record, &mut pos, 2)?; // cipher suites
+    skip_block(record, &mut pos, 1)?; // compression methods
+    let extensions_end = read_int(record, &mut pos, 2)? + pos;
+    while pos + 4 <= extensions_end {
+        let extension = read_int(record, &mut pos, 2)?;
+        let length = read_int(record, &mut pos, 2)?;
+        if extension != 0 {
+            pos += length;
+            continue;
+        }
+        // server_name: a list of (type, name) where type 0 is a host name
+        let list_end = read_int(record, &mut pos, 2)? + pos;
+        while pos + 3 <= list_end {
+            let name_type = read_int(record, &mut pos, 1)?;
+            let name_length = read_int(record, &mut pos, 2)?;
+            let name = record.get(pos..pos + name_length)?;
+            if name_type == 0 {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+            pos += name_length;
+        }
+        return None;
+    }
+    None
+}
+
+/// Reads from the client until we have the whole first TLS record.
+fn read_client_hello(
+    stream: TcpStream,
+) -> impl Future<Item = (TcpStream, Vec<u8>), Error = io::Error> {
+    future::loop_fn((stream, Vec::new()), |(stream, mut data)| {
+        read(stream, vec![0; 4096]).and_then(move |(stream, buf, n)| {
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before the TLS ClientHello",
+                ));
+            }
+            data.extend_from_slice(&buf[..n]);
+            let wanted = match data.get(3..5) {
+                Some(len) => 5 + ((len[0] as usize) << 8 | len[1] as usize),
+                None => 5,
+            };
+            if data.len() >= wanted || data.len() >= MAX_RECORD {
+                Ok(Loop::Break((stream, data)))
+            } else {
+                Ok(Loop::Continue((stream, data)))
+            }
+        })
+    })
+}
+
+fn connect(
+    resolver: &CachingResolver,
+    route: &Route,
+) -> impl Future<Item = TcpStream, Error = io::Error> {
+    let port = route.port;
+    let name = match Name::from_str(route.host.as_str()) {
+        Ok(name) => name,
+        Err(e) => {
+            return future::Either::A(future::err(io::Error::new(io::ErrorKind::InvalidInput, e)))
+        }
+    };
+    future::Either::B(
+        resolver
+            .resolve(name)
+            .and_then(move |mut addrs| match addrs.next() {
+                Some(ip) => future::Either::A(TcpStream::connect(&SocketAddr::new(ip, port))),
+                None => future::Either::B(future::err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no addresses found",
+                ))),
+            }),
+    )
+}
+
+/// Copies bytes both ways until both sides have finished sending.
+fn splice(client: TcpStream, upstream: TcpStream) -> impl Future<Item = (), Error = io::Error> {
+    let (client_read, client_write) = client.split();
+    let (upstream_read, upstream_write) = upstream.split();
+    let to_upstream = copy(client_read, upstream_write).and_then(|(_, _, w)| shutdown(w));
+    let to_client = copy(upstream_read, client_write).and_then(|(_, _, w)| shutdown(w));
+    to_upstream.join(to_client).map(|_| ())
+}
+
+fn handle(
+    client: TcpStream,
+    routes: Arc<(Vec<Route>, HostRules<Route>)>,
+    resolver: CachingResolver,
+) -> impl Future<Item = (), Error = ()> {
+    let peer = client.peer_addr().ok();
+    read_client_hello(client)
+        .and_then(move |(client, hello)| {
+            let name = server_name(&hello).map(|n| n.to_ascii_lowercase());
+            let (default_routes, rules) = &*routes;
+            let route = name.as_ref().and_then(|name| {
+                rules
+                    .select(name)
+                    .or_else(|| default_routes.iter().find(|r| &r.server_name == name))
+            });
+            let route = match route {
+                Some(route) => route,
+                None => {
+                    return future::Either::A(future::err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        match name {
+                            Some(name) => format!("no target for server name {}", name),
+                            None => "no server name in the TLS ClientHello".to_string(),
+                        },
+                    )))
+                }
+            };
+            log!(
+                "[{}] SNI {} Proxy {}:{}",
+                Utc::now(),
+                name.as_deref().unwrap_or(""),
+                route.host,
+                route.port
+            );
+            future::Either::B(
+                connect(&resolver, route)
+                    .and_then(move |upstream| write_all(upstream, hello))
+                    .and_then(move |(upstream, _)| splice(client, upstream)),
+            )
+        })
+        .map_err(move |e| match peer {
+            Some(peer) => log_error!(
+                "[{}] SNI connection from {} failed: {}",
+                Utc::now(),
+                peer,
+                e
+            ),
+            None => log_error!("[{}] SNI connection failed: {}", Utc::now(), e),
+        })
+}
+
+/// Accepts TLS connections and forwards them, still encrypted, to the target for their SNI.
+///
+/// The `rules` are tried first, then the `routes` for the host names of the target URLs.
+pub fn serve(
+    addr: &SocketAddr,
+    routes: Vec<Route>,
+    rules: HostRules<Route>,
+    resolver: CachingResolver,
+) -> io::Result<impl Future<Item = (), Error = ()>> {
+    let listener = TcpListener::bind(addr)?;
+    let routes = Arc::new((routes, rules));
+    Ok(listener
+        .incoming()
+        .map_err(|e| eprintln!("server error: {}", e))
+        .for_each(move |client| {
+            hyper::rt::spawn(handle(client, routes.clone(), resolver.clone()));
+            Ok(())
+        }))
+}
@@ -0,0 +1,320 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use chrono::{DateTime, Utc};
+use futures::future::{self, Future};
+use futures::{Async, Poll, Stream};
+use hyper::header::{HeaderValue, IF_NONE_MATCH};
+use hyper::{Body, Chunk, Method, Request, Response};
+
+/// How much of a file to read into each chunk of the response body.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A local directory serving the files for requests under a path prefix.
+pub struct StaticDir {
+    prefix: String,
+    /// The canonical path of the directory, which every file served must be inside.
+    dir: PathBuf,
+}
+
+impl StaticDir {
+    /// Parses a `PREFIX:DIR` specification from the command line.
+    pub fn parse(spec: &str) -> Result<StaticDir, String> {
+        let mut parts = spec.splitn(2, ':');
+        let prefix = parts.next().unwrap_or("").trim_matches('/');
+        let dir = match parts.next() {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => return Err(format!("Expected PREFIX:DIR but got '{}'", spec)),
+        };
+        if !dir.is_dir() {
+            return Err(format!("Static directory {} does not exist", dir.display()));
+        }
+        let dir = fs::canonicalize(&dir)
+            .map_err(|e| format!("Static directory {} cannot be read: {}", dir.display(), e))?;
+        Ok(StaticDir {
+            prefix: format!("/{}", prefix),
+            dir,
+        })
+    }
+
+    /// Maps a request path onto a file in this directory, if the path is under our prefix.
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let rest = path.strip_prefix(self.prefix.trim_end_matches('/'))?;
+        if !rest.starts_with('/') {
+            return None;
+        }
+        let mut file = self.dir.clone();
+        for segment in percent_decode(rest)?.split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return None,
+                s if s.contains('\\') => return None,
+                s => file.push(s),
+            }
+        }
+        if file == self.dir {
+            // we never list directories
+            return None;
+        }
+        Some(file)
+    }
+
+    /// Serves `file` for the request, resolving to `None` if there is no such file in this
+    /// directory, which includes a symlink to somewhere outside of it.
+    ///
+    /// The file system access happens on the blocking pool so it does not stall the reactor.
+    pub fn serve<B>(
+        &self,
+        file: PathBuf,
+        req: &Request<B>,
+    ) -> impl Future<Item = Option<Response<Body>>, Error = hyper::Error> {
+        let root = self.dir.clone();
+        let if_none_match = req.headers().get(IF_NONE_MATCH).cloned();
+        let head = req.method() == Method::HEAD;
+        future::poll_fn(move || {
+            let read = || read_file(&root, &file, if_none_match.as_ref(), head);
+            match tokio_threadpool::blocking(read) {
+                Ok(Async::Ready(response)) => Ok(Async::Ready(response)),
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                // not running on a thread pool, so just read it here
+                Err(_) => Ok(Async::Ready(read_file(
+                    &root,
+                    &file,
+                    if_none_match.as_ref(),
+                    head,
+                ))),
+            }
+        })
+    }
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") | Some("mjs") => "application/javascript; charset=utf-8",
+        Some("json") | Some("map") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("otf") => "font/otf",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("mp4") => "video/mp4",
+        Some("webm") => "video/webm",
+        Some("mp3") => "audio/mpeg",
+        Some("zip") => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+fn etag_matches(if_none_match: &HeaderValue, etag: &str) -> bool {
+    match if_none_match.to_str() {
+        Ok(value) => value
+            .split(',')
+            .map(|v| v.trim().trim_start_matches("W/"))
+            .any(|v| v == "*" || v == etag),
+        Err(_) => false,
+    }
+}
+
+fn read_file(
+    root: &Path,
+    file: &Path,
+    if_none_match: Option<&HeaderValue>,
+    head: bool,
+) -> Option<Response<Body>> {
+    // a symlink could otherwise lead anywhere on the file system
+    let file = fs::canonicalize(file).ok()?;
+    if !file.starts_with(root) {
+        return None;
+    }
+    let opened = File::open(&file).ok()?;
+    let metadata = opened.metadata().ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    let modified = metadata.modified().ok()?;
+    let mtime = modified.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let etag = format!("\"{:x}-{:x}\"", mtime, metadata.len());
+    let last_modified = DateTime::<Utc>::from(modified)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string();
+    let mut response = Response::builder();
+    response
+        .header("ETag", etag.as_str())
+        .header("Last-Modified", last_modified.as_str());
+    if if_none_match.is_some_and(|v| etag_matches(v, etag.as_str())) {
+        return Some(response.status(304).body(Body::empty()).unwrap());
+    }
+    response
+        .header("Content-Type", content_type(&file))
+        .header("Content-Length", metadata.len());
+    if head {
+        return Some(response.body(Body::empty()).unwrap());
+    }
+    let body = Body::wrap_stream(FileBody { file: opened });
+    Some(response.body(body).unwrap())
+}
+
+/// Streams a file as a response body, a chunk at a time from the blocking pool.
+struct FileBody {
+    file: File,
+}
+
+impl FileBody {
+    fn read_chunk(file: &mut File) -> io::Result<Option<Chunk>> {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        buf.truncate(read);
+        Ok(Some(Chunk::from(buf)))
+    }
+}
+
+impl Stream for FileBody {
+    type Item = Chunk;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, io::Error> {
+        let file = &mut self.file;
+        match tokio_threadpool::blocking(|| FileBody::read_chunk(file)) {
+            Ok(Async::Ready(chunk)) => chunk.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            // not running on a thread pool, so just read it here
+            Err(_) => FileBody::read_chunk(&mut self.file).map(Async::Ready),
+        }
+    }
+}
+
+/// Whether requests with this method can be served from a static directory; the others
+/// are left to the target, which may well accept a `POST` to the same path.
+pub fn serves(method: &Method) -> bool {
+    method == Method::GET || method == Method::HEAD
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(prefix: &str) -> StaticDir {
+        StaticDir {
+            prefix: prefix.to_string(),
+            dir: PathBuf::from("/srv/static"),
+        }
+    }
+
+    #[test]
+    fn percent_decode_decodes_escapes() {
+        assert_eq!(percent_decode("/a%20b%2Fc").as_deref(), Some("/a b/c"));
+        assert_eq!(percent_decode("/100%").as_deref(), Some("/100%"));
+        assert_eq!(percent_decode("/%zz/"), None);
+        assert_eq!(percent_decode("/%ff/"), None);
+    }
+
+    #[test]
+    fn resolves_paths_under_the_prefix() {
+        let dir = dir("/assets");
+        assert_eq!(
+            dir.resolve("/assets/css/site.css"),
+            Some(PathBuf::from("/srv/static/css/site.css"))
+        );
+        assert_eq!(
+            dir.resolve("/assets/./a%20b.txt"),
+            Some(PathBuf::from("/srv/static/a b.txt"))
+        );
+        assert_eq!(dir.resolve("/assetsx/site.css"), None);
+        assert_eq!(dir.resolve("/other/site.css"), None);
+    }
+
+    #[test]
+    fn never_resolves_outside_the_directory_or_to_it() {
+        let dir = dir("/assets");
+        assert_eq!(dir.resolve("/assets/../etc/passwd"), None);
+        assert_eq!(dir.resolve("/assets/%2e%2e/etc/passwd"), None);
+        assert_eq!(dir.resolve("/assets/..%5Cetc"), None);
+        assert_eq!(dir.resolve("/assets/"), None);
+    }
+
+    #[test]
+    fn a_root_prefix_serves_everything() {
+        assert_eq!(
+            dir("/").resolve("/index.html"),
+            Some(PathBuf::from("/srv/static/index.html"))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn streams_files_but_not_symlinks_out_of_the_directory() {
+        let base = std::env::temp_dir().join(format!("static-files-{}", std::process::id()));
+        let root = base.join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("inside.txt"), "inside").unwrap();
+        fs::write(base.join("outside.txt"), "outside").unwrap();
+        std::os::unix::fs::symlink(base.join("outside.txt"), root.join("escape.txt")).unwrap();
+        std::os::unix::fs::symlink(root.join("inside.txt"), root.join("link.txt")).unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+
+        let body = |name: &str| {
+            read_file(&root, &root.join(name), None, false).map(|response| {
+                let body = response.into_body().concat2().wait().unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            })
+        };
+        assert_eq!(body("inside.txt").as_deref(), Some("inside"));
+        assert_eq!(body("link.txt").as_deref(), Some("inside"));
+        assert_eq!(body("escape.txt"), None);
+        assert_eq!(body("missing.txt"), None);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn only_serves_get_and_head() {
+        assert!(serves(&Method::GET));
+        assert!(serves(&Method::HEAD));
+        assert!(!serves(&Method::POST));
+    }
+}
@@ -0,0 +1,159 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use chrono::Utc;
+use hyper::header::HeaderName;
+use hyper::{HeaderMap, Request, Response};
+use rand::Rng;
+use serde_json::{json, Map, Value};
+
+use crate::forwarded::ClientIp;
+
+/// How many records may queue up for a slow tap client before we start dropping them.
+const CLIENT_BACKLOG: usize = 1024;
+
+/// Hands records to the thread writing to one tap client.
+type Client = SyncSender<Arc<Vec<u8>>>;
+
+/// Headers whose values are replaced before they are written to the tap.
+const REDACTED_HEADERS: [&str; 4] = [
+    "Authorization",
+    "Proxy-Authorization",
+    "Cookie",
+    "Set-Cookie",
+];
+
+/// Streams a copy of the proxied requests and responses to clients of a Unix socket.
+///
+/// Each record is a JSON object prefixed by its length as a 4 byte big-endian integer.
+/// Records are handed to a writer thread per client and dropped if that client is not
+/// keeping up, so tapping never holds up the request being proxied.
+#[derive(Clone)]
+pub struct Tap {
+    clients: Arc<Mutex<Vec<Client>>>,
+    sample_rate: u32,
+    redact: bool,
+}
+
+/// A request that has been picked to be tapped, waiting for its response.
+pub struct Tapped {
+    started: Instant,
+    record: Map<String, Value>,
+}
+
+impl Tap {
+    /// Listens on `path`, replacing any socket left over from a previous run.
+    pub fn bind(path: &str, sample_rate: u32, redact: bool) -> io::Result<Tap> {
+        if fs::metadata(path).is_ok() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let tap = Tap {
+            clients: Arc::new(Mutex::new(Vec::new())),
+            sample_rate,
+            redact,
+        };
+        let clients = tap.clients.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (sender, receiver) = mpsc::sync_channel(CLIENT_BACKLOG);
+                clients.lock().unwrap().push(sender);
+                thread::spawn(move || write_records(stream, receiver.iter()));
+            }
+        });
+        Ok(tap)
+    }
+
+    /// Starts tapping the request, unless nobody is listening or it is not sampled.
+    pub fn begin<B>(&self, req: &Request<B>) -> Option<Tapped> {
+        if self.clients.lock().unwrap().is_empty()
+            || rand::thread_rng().gen_range(0, 100) >= self.sample_rate
+        {
+            return None;
+        }
+        let mut record = Map::new();
+        record.insert("timestamp".into(), json!(Utc::now().to_rfc3339()));
+        if let Some(ip) = ClientIp::of(req) {
+            record.insert("client_ip".into(), json!(ip.to_string()));
+        }
+        record.insert("method".into(), json!(req.method().as_str()));
+        record.insert("path".into(), json!(req.uri().to_string()));
+        record.insert("request_headers".into(), self.headers(req.headers()));
+        Some(Tapped {
+            started: Instant::now(),
+            record,
+        })
+    }
+
+    /// Completes the record with the response and sends it to every tap client.
+    pub fn finish<B>(&self, tapped: Tapped, response: &Response<B>) {
+        let Tapped {
+            started,
+            mut record,
+        } = tapped;
+        record.insert("status".into(), json!(response.status().as_u16()));
+        record.insert("response_headers".into(), self.headers(response.headers()));
+        record.insert(
+            "duration_ms".into(),
+            json!(started.elapsed().as_secs_f64() * 1000.0),
+        );
+        let json = Value::Object(record).to_string();
+        let mut frame = Vec::with_capacity(json.len() + 4);
+        frame.extend_from_slice(&(json.len() as u32).to_be_bytes());
+        frame.extend_from_slice(json.as_bytes());
+        let frame = Arc::new(frame);
+        // a full backlog just loses this record, a closed one means the client went away
+        self.clients.lock().unwrap().retain(|client| {
+            !matches!(
+                client.try_send(frame.clone()),
+                Err(mpsc::TrySendError::Disconnected(_))
+            )
+        });
+    }
+
+    fn headers(&self, headers: &HeaderMap) -> Value {
+        let mut map = Map::new();
+        for name in headers.keys() {
+            let value = if self.redact && is_redacted(name) {
+                "REDACTED".to_string()
+            } else {
+                headers
+                    .get_all(name)
+                    .iter()
+                    .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            map.insert(name.as_str().to_string(), json!(value));
+        }
+        Value::Object(map)
+    }
+}
+
+fn is_redacted(name: &HeaderName) -> bool {
+    REDACTED_HEADERS
+        .iter()
+        .any(|h| h.eq_ignore_ascii_case(name.as_str()))
+}
+
+fn write_records<I: Iterator<Item = Arc<Vec<u8>>>>(mut stream: UnixStream, records: I) {
+    for record in records {
+        if stream.write_all(&record).is_err() {
+            // dropping the receiver tells `finish` to forget about this client
+            return;
+        }
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use futures::{Async, Poll, Stream};
+use hyper::body::Payload;
+use hyper::header::HeaderValue;
+use hyper::{Body, Chunk, Response};
+
+use crate::connector::ConnectTiming;
+use crate::grpc;
+use crate::logging::RequestTag;
+
+/// Where the time went while proxying a request.
+pub struct RequestTiming {
+    /// Logs the timings once the response has been sent.
+    pub log: bool,
+    /// Adds `X-Proxy-Connect-Time` and `X-Proxy-TTFB` to the response.
+    pub headers: bool,
+}
+
+/// Time spent on a connection opened for this request, or none if it reused one.
+fn connect_time<B>(response: &Response<B>, start: Instant) -> Duration {
+    match response.extensions().get::<ConnectTiming>() {
+        Some(connect) if connect.at >= start => connect.took,
+        _ => Duration::from_millis(0),
+    }
+}
+
+fn millis(duration: Option<Duration>) -> String {
+    match duration {
+        Some(duration) => duration.as_millis().to_string(),
+        None => "-".to_string(),
+    }
+}
+
+impl RequestTiming {
+    /// Records the timings of a response whose headers have just arrived, for a request
+    /// that arrived at `start`.
+    ///
+    /// The last byte is only known once the body has been sent, so the body is wrapped to
+    /// log then; gRPC bodies are left alone as the wrapping would drop their trailers, and
+    /// the log line for them is written straight away without the body timings.
+    pub fn record(
+        &self,
+        mut response: Response<Body>,
+        start: Instant,
+        tag: RequestTag,
+        uri: String,
+    ) -> Response<Body> {
+        let connect = connect_time(&response, start);
+        let first_byte = start.elapsed();
+        if self.headers {
+            let headers = response.headers_mut();
+            headers.insert(
+                "X-Proxy-Connect-Time",
+                HeaderValue::from(connect.as_millis() as u64),
+            );
+            headers.insert(
+                "X-Proxy-TTFB",
+                HeaderValue::from(first_byte.as_millis() as u64),
+            );
+        }
+        if !self.log {
+            return response;
+        }
+        let timed = TimedBody {
+            start,
+            tag,
+            uri,
+            connect,
+            first_byte,
+            last_byte: None,
+        };
+        if grpc::is_grpc(response.headers()) {
+            // dropping it logs it
+            return response;
+        }
+        let (parts, body) = response.into_parts();
+        let body = TimedStream {
+            remaining: body.content_length(),
+            inner: body,
+            timed,
+        };
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+}
+
+/// The timings of a request, logged when dropped.
+struct TimedBody {
+    start: Instant,
+    tag: RequestTag,
+    uri: String,
+    connect: Duration,
+    first_byte: Duration,
+    last_byte: Option<Duration>,
+}
+
+impl Drop for TimedBody {
+    fn drop(&mut self) {
+        let total = self.last_byte.map(|_| self.start.elapsed());
+        log!(
+            "[{}] {}{} Timing time_to_upstream_connect_ms={} time_to_first_byte_ms={} time_to_last_byte_ms={} total_duration_ms={}",
+            Utc::now(),
+            self.tag,
+            self.uri,
+            self.connect.as_millis(),
+            self.first_byte.as_millis(),
+            millis(self.last_byte),
+            millis(total)
+        );
+    }
+}
+
+/// A response body that notes when its last byte was read.
+///
+/// The server stops reading a body with a known length once it has that many bytes, so
+/// the end of those is found by counting rather than by waiting for the end of the stream.
+struct TimedStream {
+    inner: Body,
+    remaining: Option<u64>,
+    timed: TimedBody,
+}
+
+impl Stream for TimedStream {
+    type Item = Chunk;
+    type Error = hyper::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, hyper::Error> {
+        let polled = self.inner.poll()?;
+        let last = match (&polled, self.remaining.as_mut()) {
+            (Async::Ready(Some(chunk)), Some(remaining)) => {
+                *remaining = remaining.saturating_sub(chunk.len() as u64);
+                *remaining == 0
+            }
+            (Async::Ready(None), _) => true,
+            _ => false,
+        };
+        if last && self.timed.last_byte.is_none() {
+            self.timed.last_byte = Some(self.timed.start.elapsed());
+        }
+        Ok(polled)
+    }
+}
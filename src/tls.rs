@@ -0,0 +1,239 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use futures::{Future, Stream};
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientConfig, PrivateKey, ProtocolVersion, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, SupportedCipherSuite, TLSError, ALL_CIPHERSUITES,
+};
+use tokio_timer::Interval;
+use webpki::{DNSNameRef, EndEntityCert, TLSServerTrustAnchors};
+
+/// The signature algorithms rustls accepts for server certificates.
+static SUPPORTED_SIG_ALGS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// Verifies the server certificate chain against the trusted CAs but not the host name.
+struct NoHostnameVerifier;
+
+impl ServerCertVerifier for NoHostnameVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let (end_entity, chain) = match presented_certs.split_first() {
+            Some(certs) => certs,
+            None => return Err(TLSError::NoCertificatesPresented),
+        };
+        let cert = EndEntityCert::from(&end_entity.0).map_err(TLSError::WebPKIError)?;
+        let chain: Vec<&[u8]> = chain.iter().map(|c| c.0.as_ref()).collect();
+        let trust_roots: Vec<webpki::TrustAnchor> =
+            roots.roots.iter().map(|r| r.to_trust_anchor()).collect();
+        let now = webpki::Time::try_from(std::time::SystemTime::now())
+            .map_err(|_| TLSError::FailedToGetCurrentTime)?;
+        cert.verify_is_valid_tls_server_cert(
+            SUPPORTED_SIG_ALGS,
+            &TLSServerTrustAnchors(&trust_roots),
+            &chain,
+            now,
+        )
+        .map_err(TLSError::WebPKIError)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The settings used to build the TLS configuration for upstream connections.
+#[derive(Clone, Default)]
+pub struct UpstreamTls {
+    pub ca_cert: Option<String>,
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    /// Accept any certificate from a trusted CA, whatever host name it was issued for.
+    pub skip_hostname_verification: bool,
+    /// The cipher suites to offer, or all that rustls supports if empty.
+    pub cipher_suites: Vec<&'static SupportedCipherSuite>,
+    /// Negotiate HTTP/2 with ALPN.
+    pub http2: bool,
+    /// The TLS versions to offer, or the rustls defaults if empty.
+    pub versions: Vec<ProtocolVersion>,
+}
+
+fn parse_version(version: &str) -> Result<ProtocolVersion, String> {
+    match version.trim() {
+        "1.2" => Ok(ProtocolVersion::TLSv1_2),
+        "1.3" => Ok(ProtocolVersion::TLSv1_3),
+        v => Err(format!(
+            "TLS version is supposed to be 1.2 or 1.3 but got {}",
+            v
+        )),
+    }
+}
+
+/// The TLS versions from `min` to `max`, e.g. `1.2` and `1.3`.
+pub fn parse_versions(min: &str, max: &str) -> Result<Vec<ProtocolVersion>, String> {
+    let (min, max) = (parse_version(min)?, parse_version(max)?);
+    let versions: Vec<ProtocolVersion> = [ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2]
+        .iter()
+        .filter(|v| v.get_u16() >= min.get_u16() && v.get_u16() <= max.get_u16())
+        .cloned()
+        .collect();
+    if versions.is_empty() {
+        return Err("The minimum TLS version is above the maximum TLS version".to_string());
+    }
+    Ok(versions)
+}
+
+/// The standard (IANA) name of a cipher suite.
+fn cipher_suite_name(suite: &SupportedCipherSuite) -> String {
+    // rustls prefixes the TLS 1.3 suites with TLS13_ to tell them apart
+    format!("{:?}", suite.suite).replacen("TLS13_", "TLS_", 1)
+}
+
+/// Parses a comma separated list of cipher suite names, e.g. `TLS_AES_256_GCM_SHA384`.
+pub fn parse_cipher_suites(list: &str) -> Result<Vec<&'static SupportedCipherSuite>, String> {
+    let mut suites = Vec::new();
+    for name in list.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+        let name = name.to_ascii_uppercase().replacen("TLS13_", "TLS_", 1);
+        match ALL_CIPHERSUITES
+            .iter()
+            .find(|s| cipher_suite_name(s) == name)
+        {
+            Some(suite) => suites.push(*suite),
+            None => {
+                let supported: Vec<String> = ALL_CIPHERSUITES
+                    .iter()
+                    .map(|s| cipher_suite_name(s))
+                    .collect();
+                return Err(format!(
+                    "Unknown cipher suite {}, expected one of: {}",
+                    name,
+                    supported.join(", ")
+                ));
+            }
+        }
+    }
+    if suites.is_empty() {
+        return Err("No cipher suites given".to_string());
+    }
+    Ok(suites)
+}
+
+/// The TLS configuration for connections other than to the upstream, such as to the
+/// metrics Pushgateway, which trusts the usual public CAs and none of the upstream options.
+pub fn default_client_config() -> ClientConfig {
+    let mut config = ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    config
+}
+
+impl UpstreamTls {
+    pub fn client_config(&self) -> Result<ClientConfig, String> {
+        let mut config = ClientConfig::new();
+        config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+        if let Some(ca_cert) = &self.ca_cert {
+            for cert in load_certs(ca_cert)? {
+                config
+                    .root_store
+                    .add(&cert)
+                    .map_err(|e| format!("Invalid CA certificate in {}: {}", ca_cert, e))?;
+            }
+        }
+        if let (Some(cert), Some(key)) = (&self.client_cert, &self.client_key) {
+            config.set_single_client_cert(load_certs(cert)?, load_private_key(key)?);
+        }
+        if !self.cipher_suites.is_empty() {
+            config.ciphersuites = self.cipher_suites.clone();
+        }
+        if !self.versions.is_empty() {
+            config.versions = self.versions.clone();
+        }
+        if self.http2 {
+            config.set_protocols(&[b"h2".to_vec()]);
+        }
+        if self.skip_hostname_verification {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoHostnameVerifier));
+        }
+        Ok(config)
+    }
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|e| format!("Could not open {}: {}", path, e))?;
+    match pemfile::certs(&mut BufReader::new(file)) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(format!("No PEM certificates found in {}", path)),
+    }
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, String> {
+    let open = || File::open(path).map_err(|e| format!("Could not open {}: {}", path, e));
+    if let Ok(mut keys) = pemfile::pkcs8_private_keys(&mut BufReader::new(open()?)) {
+        if !keys.is_empty() {
+            return Ok(keys.remove(0));
+        }
+    }
+    if let Ok(mut keys) = pemfile::rsa_private_keys(&mut BufReader::new(open()?)) {
+        if !keys.is_empty() {
+            return Ok(keys.remove(0));
+        }
+    }
+    Err(format!("No PEM private key found in {}", path))
+}
+
+/// Periodically rebuilds the upstream TLS configuration from disk and swaps it into `current`.
+///
+/// A failed reload keeps the previous configuration in place.
+pub fn reload_every(
+    upstream_tls: UpstreamTls,
+    interval: Duration,
+    current: Arc<RwLock<Arc<ClientConfig>>>,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(|e| log_error!("client certificate reload timer error: {}", e))
+        .for_each(move |_| {
+            match upstream_tls.client_config() {
+                Ok(config) => {
+                    *current.write().unwrap() = Arc::new(config);
+                    log!("[{}] Reloaded upstream TLS certificates", Utc::now());
+                }
+                Err(e) => log_error!(
+                    "[{}] Could not reload upstream TLS certificates: {}",
+                    Utc::now(),
+                    e
+                ),
+            }
+            Ok(())
+        })
+}
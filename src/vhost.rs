@@ -0,0 +1,71 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use regex::Regex;
+
+/// Picks a target by the host name the client asked for, i.e. the TLS SNI or the `Host` header.
+pub struct HostRules<T> {
+    rules: Vec<(Regex, T)>,
+}
+
+impl<T> Default for HostRules<T> {
+    fn default() -> HostRules<T> {
+        HostRules { rules: Vec::new() }
+    }
+}
+
+impl<T> HostRules<T> {
+    /// Adds a `HOSTNAME_REGEX:TARGET_URL` rule from the command line, using `target` to turn
+    /// the URL into whatever we route to.
+    pub fn add<F>(&mut self, spec: &str, target: F) -> Result<(), String>
+    where
+        F: FnOnce(&str) -> Result<T, String>,
+    {
+        let mut parts = spec.splitn(2, ':');
+        let pattern = parts.next().unwrap_or("");
+        let url = match parts.next() {
+            Some(url) if !pattern.is_empty() => url,
+            _ => {
+                return Err(format!(
+                    "Expected HOSTNAME_REGEX:TARGET_URL but got '{}'",
+                    spec
+                ))
+            }
+        };
+        let pattern = Regex::new(pattern)
+            .map_err(|e| format!("Invalid host name pattern in '{}': {}", spec, e))?;
+        self.rules.push((pattern, target(url)?));
+        Ok(())
+    }
+
+    /// The target of the rule with the longest match on `host`, the first one defined on a tie.
+    pub fn select(&self, host: &str) -> Option<&T> {
+        let host = host.to_ascii_lowercase();
+        let mut best: Option<(usize, &T)> = None;
+        for (pattern, target) in &self.rules {
+            if let Some(m) = pattern.find(host.as_str()) {
+                if best.is_none_or(|(len, _)| m.len() > len) {
+                    best = Some((m.len(), target));
+                }
+            }
+        }
+        best.map(|(_, target)| target)
+    }
+}
+
+/// The host name from a `Host` header, without any port.
+pub fn host_name(host: &str) -> &str {
+    if host.starts_with('[') {
+        match host.find(']') {
+            Some(end) => &host[..=end],
+            None => host,
+        }
+    } else {
+        host.split(':').next().unwrap_or(host)
+    }
+}
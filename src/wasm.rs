@@ -0,0 +1,170 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Request body transforms written in WebAssembly.
+//!
+//! The module gets no imports, so it has no access to the system, and must export:
+//!
+//! * `memory`
+//! * `alloc(len: i32) -> i32` returning space for `len` bytes in `memory`
+//! * `transform_request(body_ptr: i32, body_len: i32, headers_ptr: i32, headers_len: i32) -> i64`
+//!   returning the new body as `(ptr << 32) | len`
+//!
+//! The headers are passed as `name: value` lines separated by `\n`.
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::Future;
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+
+use crate::body;
+use crate::errors::{ErrorFormat, ErrorStyle};
+use crate::logging::RequestTag;
+use crate::Transformed;
+
+fn encode_headers(headers: &HeaderMap) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for (name, value) in headers {
+        if let Ok(value) = value.to_str() {
+            encoded.extend_from_slice(format!("{}: {}\n", name, value).as_bytes());
+        }
+    }
+    encoded
+}
+
+fn transform_failed(style: &ErrorStyle) -> Response<Body> {
+    style.response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Request body transform failed",
+        ErrorFormat::Text,
+    )
+}
+
+#[cfg(feature = "wasm")]
+mod runtime {
+    use wasmtime::{Config, Engine, Instance, Module, Store};
+
+    /// A compiled WebAssembly transform, instantiated afresh for every request.
+    pub struct Transform {
+        engine: Engine,
+        module: Module,
+        fuel: Option<u64>,
+    }
+
+    impl Transform {
+        pub fn load(path: &str, fuel: Option<u64>) -> Result<Transform, String> {
+            let mut config = Config::new();
+            config.consume_fuel(fuel.is_some());
+            let engine = Engine::new(&config).map_err(|e| e.to_string())?;
+            let module = Module::from_file(&engine, path)
+                .map_err(|e| format!("Could not load {}: {}", path, e))?;
+            Ok(Transform {
+                engine,
+                module,
+                fuel,
+            })
+        }
+
+        pub fn run(&self, body: &[u8], headers: &[u8]) -> Result<Vec<u8>, String> {
+            // the root cause says why, e.g. that it ran out of fuel, without the wasm backtrace
+            self.call(body, headers)
+                .map_err(|e| e.root_cause().to_string())
+        }
+
+        fn call(&self, body: &[u8], headers: &[u8]) -> wasmtime::Result<Vec<u8>> {
+            let mut store = Store::new(&self.engine, ());
+            if let Some(fuel) = self.fuel {
+                store.set_fuel(fuel)?;
+            }
+            let instance = Instance::new(&mut store, &self.module, &[])?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .ok_or_else(|| wasmtime::Error::msg("the module does not export its memory"))?;
+            let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+            let transform = instance
+                .get_typed_func::<(i32, i32, i32, i32), i64>(&mut store, "transform_request")?;
+            let copy_in = |store: &mut Store<()>, data: &[u8]| -> wasmtime::Result<i32> {
+                let ptr = alloc.call(&mut *store, data.len() as i32)?;
+                memory.write(&mut *store, ptr as u32 as usize, data)?;
+                Ok(ptr)
+            };
+            let body_ptr = copy_in(&mut store, body)?;
+            let headers_ptr = copy_in(&mut store, headers)?;
+            let result = transform.call(
+                &mut store,
+                (
+                    body_ptr,
+                    body.len() as i32,
+                    headers_ptr,
+                    headers.len() as i32,
+                ),
+            )?;
+            let mut output = vec![0; result as u32 as usize];
+            memory.read(&store, (result >> 32) as u32 as usize, &mut output)?;
+            Ok(output)
+        }
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+mod runtime {
+    pub enum Transform {}
+
+    impl Transform {
+        pub fn load(_path: &str, _fuel: Option<u64>) -> Result<Transform, String> {
+            Err("This build does not support WebAssembly, rebuild with --features wasm".to_string())
+        }
+
+        pub fn run(&self, _body: &[u8], _headers: &[u8]) -> Result<Vec<u8>, String> {
+            match *self {}
+        }
+    }
+}
+
+/// Replaces request bodies with the output of a WebAssembly module.
+pub struct RequestTransform(runtime::Transform);
+
+impl RequestTransform {
+    /// Compiles the module, which may use at most `fuel` units of work for each request.
+    pub fn load(path: &str, fuel: Option<u64>) -> Result<RequestTransform, String> {
+        runtime::Transform::load(path, fuel).map(RequestTransform)
+    }
+
+    /// Buffers the request body and runs it through the module.
+    ///
+    /// If the module fails, e.g. by running out of fuel, the client gets a 500 instead.
+    /// Resolves to `None` if the body is more than `limit` bytes.
+    pub fn apply(
+        self: Arc<Self>,
+        req: Request<Body>,
+        tag: RequestTag,
+        limit: u64,
+    ) -> impl Future<Item = Option<Transformed>, Error = hyper::Error> {
+        let style = ErrorStyle::of(&req);
+        body::buffer_request(req, limit).map(move |buffered| {
+            let (mut parts, body) = buffered?;
+            Some(match self.0.run(&body, &encode_headers(&parts.headers)) {
+                Ok(body) => {
+                    parts.headers.insert(CONTENT_LENGTH, body.len().into());
+                    Transformed::Forward(Request::from_parts(parts, Body::from(body)))
+                }
+                Err(e) => {
+                    log_error!(
+                        "[{}] {}Request body transform failed: {}",
+                        Utc::now(),
+                        tag,
+                        e
+                    );
+                    Transformed::Respond(transform_failed(&style))
+                }
+            })
+        })
+    }
+}
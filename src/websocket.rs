@@ -0,0 +1,353 @@
+// Copyright 2019 Stephen Connolly.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE.txt or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT.txt or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use futures::future::Future;
+use futures::{try_ready, Async, Poll};
+use hyper::header::{HeaderValue, CONNECTION, SEC_WEBSOCKET_PROTOCOL, UPGRADE};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use rand::Rng;
+use tokio_io::io::{copy, shutdown};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use crate::logging::RequestTag;
+
+/// Whether the request asks to upgrade the connection to a WebSocket.
+pub fn is_upgrade<B>(req: &Request<B>) -> bool {
+    let upgrade = req
+        .headers()
+        .get(UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("websocket"));
+    upgrade
+        && req
+            .headers()
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+fn subprotocols(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .collect()
+}
+
+/// The subprotocols offered by the client that are not in the `allowed` list.
+pub fn unsupported_subprotocols<B>(req: &Request<B>, allowed: &[String]) -> Vec<String> {
+    subprotocols(req.headers())
+        .into_iter()
+        .filter(|p| !allowed.contains(p))
+        .collect()
+}
+
+/// A WebSocket handshake on its way to the upstream.
+///
+/// It is carried in the request extensions so that whichever attempt gets the upstream to
+/// switch protocols can join the two connections together.
+#[derive(Clone)]
+pub struct Handshake {
+    client: Arc<Mutex<Option<OnUpgrade>>>,
+    offered: Vec<String>,
+    max_frame_size: Option<u64>,
+}
+
+impl Handshake {
+    /// Takes the client connection out of a WebSocket upgrade request.
+    ///
+    /// Once upgraded, frames with a payload over `max_frame_size` bytes close the connection.
+    pub fn begin(req: Request<Body>, max_frame_size: Option<u64>) -> Request<Body> {
+        let (mut parts, body) = req.into_parts();
+        let handshake = Handshake {
+            client: Arc::new(Mutex::new(Some(body.on_upgrade()))),
+            offered: subprotocols(&parts.headers),
+            max_frame_size,
+        };
+        parts.extensions.insert(handshake);
+        Request::from_parts(parts, Body::empty())
+    }
+
+    pub fn of<B>(req: &Request<B>) -> Option<Handshake> {
+        req.extensions().get::<Handshake>().cloned()
+    }
+
+    /// Puts back the headers that ask the upstream to upgrade, which are otherwise hop-by-hop.
+    pub fn request_upgrade(headers: &mut HeaderMap) {
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    }
+
+    /// Joins the client to the upstream if the upstream agreed to switch protocols.
+    pub fn complete(&self, response: Response<Body>, tag: RequestTag) -> Response<Body> {
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return response;
+        }
+        let client = match self.client.lock().unwrap().take() {
+            Some(client) => client,
+            None => return response,
+        };
+        let (mut parts, body) = response.into_parts();
+        if let Some(accepted) = subprotocols(&parts.headers).first() {
+            if !self.offered.contains(accepted) {
+                log_error!(
+                    "[{}] {}WARN The upstream accepted WebSocket subprotocol {} which was not offered",
+                    Utc::now(),
+                    tag,
+                    accepted
+                );
+            }
+        }
+        Handshake::request_upgrade(&mut parts.headers);
+        let max_frame_size = self.max_frame_size;
+        hyper::rt::spawn(
+            client
+                .join(body.on_upgrade())
+                .map_err(|e| log_error!("WebSocket upgrade error: {}", e))
+                .and_then(move |(client, upstream)| {
+                    let (client_read, client_write) = client.split();
+                    let (upstream_read, upstream_write) = upstream.split();
+                    let copied: Box<dyn Future<Item = (), Error = io::Error> + Send> =
+                        match max_frame_size {
+                            Some(max) => Box::new(
+                                FrameCopy::new(
+                                    client_read,
+                                    upstream_write,
+                                    max,
+                                    Peer::Client,
+                                    tag.clone(),
+                                )
+                                .join(FrameCopy::new(
+                                    upstream_read,
+                                    client_write,
+                                    max,
+                                    Peer::Upstream,
+                                    tag,
+                                ))
+                                .map(|_| ()),
+                            ),
+                            None => Box::new(
+                                copy(client_read, upstream_write)
+                                    .and_then(|(_, _, writer)| shutdown(writer))
+                                    .join(
+                                        copy(upstream_read, client_write)
+                                            .and_then(|(_, _, writer)| shutdown(writer)),
+                                    )
+                                    .map(|_| ()),
+                            ),
+                        };
+                    copied.map_err(|e| log_error!("WebSocket connection error: {}", e))
+                }),
+        );
+        Response::from_parts(parts, Body::empty())
+    }
+}
+
+/// The close status for a message too big to process.
+const MESSAGE_TOO_BIG: u16 = 1009;
+
+/// Which side of the connection frames come from.
+#[derive(Clone, Copy)]
+enum Peer {
+    Client,
+    Upstream,
+}
+
+/// The header length and payload length of the frame at the start of `data`, if the whole
+/// header is there.
+fn frame_header(data: &[u8]) -> Option<(usize, u64)> {
+    if data.len() < 2 {
+        return None;
+    }
+    let mask = if data[1] & 0x80 != 0 { 4 } else { 0 };
+    let (extended, len) = match data[1] & 0x7f {
+        126 => (
+            2,
+            u64::from(u16::from_be_bytes([*data.get(2)?, *data.get(3)?])),
+        ),
+        127 => {
+            let mut len = [0; 8];
+            len.copy_from_slice(data.get(2..10)?);
+            (8, u64::from_be_bytes(len))
+        }
+        len => (0, u64::from(len)),
+    };
+    let header = 2 + extended + mask;
+    if data.len() < header {
+        return None;
+    }
+    Some((header, len))
+}
+
+/// A close frame, which frames sent to the upstream must be masked.
+fn close_frame(status: u16, masked: bool) -> Vec<u8> {
+    let mut payload = status.to_be_bytes().to_vec();
+    payload.extend_from_slice(b"Message too big");
+    let mut frame = vec![0x88, payload.len() as u8];
+    if masked {
+        let mask: [u8; 4] = rand::thread_rng().gen();
+        frame[1] |= 0x80;
+        frame.extend_from_slice(&mask);
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+    }
+    frame.extend_from_slice(&payload);
+    frame
+}
+
+/// Copies frames from one side to the other, replacing the first frame that is too big
+/// with a close frame and then stopping.
+///
+/// The other side answers the close, which reaches the sender of the frame through the copy
+/// going the other way, so both ends see why the connection is closing.
+struct FrameCopy<R, W> {
+    reader: R,
+    writer: W,
+    from: Peer,
+    tag: RequestTag,
+    max: u64,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    /// How much of the payload of the current frame is still to be copied.
+    remaining: u64,
+    done: bool,
+}
+
+impl<R, W> FrameCopy<R, W> {
+    fn new(reader: R, writer: W, max: u64, from: Peer, tag: RequestTag) -> FrameCopy<R, W> {
+        FrameCopy {
+            reader,
+            writer,
+            from,
+            tag,
+            max,
+            input: Vec::new(),
+            output: Vec::new(),
+            written: 0,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    /// Moves what can be sent on from the input to the output, returning `false` if more
+    /// input is needed.
+    fn process(&mut self) -> bool {
+        if self.input.is_empty() {
+            return false;
+        }
+        if self.remaining > 0 {
+            let len = (self.remaining.min(self.input.len() as u64)) as usize;
+            self.output.extend(self.input.drain(..len));
+            self.remaining -= len as u64;
+            return true;
+        }
+        match frame_header(&self.input) {
+            Some((_, len)) if len > self.max => {
+                log_error!(
+                    "[{}] {}WARN Closing WebSocket after a {} byte frame from the {}, over the {} byte limit",
+                    Utc::now(),
+                    self.tag,
+                    len,
+                    match self.from {
+                        Peer::Client => "client",
+                        Peer::Upstream => "upstream",
+                    },
+                    self.max
+                );
+                let masked = match self.from {
+                    Peer::Client => true,
+                    Peer::Upstream => false,
+                };
+                self.output = close_frame(MESSAGE_TOO_BIG, masked);
+                self.input.clear();
+                self.done = true;
+                true
+            }
+            Some((header, len)) => {
+                self.output.extend(self.input.drain(..header));
+                self.remaining = len;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<R: AsyncRead, W: AsyncWrite> Future for FrameCopy<R, W> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            while self.written < self.output.len() {
+                let n = try_ready!(self.writer.poll_write(&self.output[self.written..]));
+                if n == 0 {
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                self.written += n;
+            }
+            self.output.clear();
+            self.written = 0;
+            if self.done {
+                try_ready!(self.writer.shutdown());
+                return Ok(Async::Ready(()));
+            }
+            if self.process() {
+                continue;
+            }
+            try_ready!(self.writer.poll_flush());
+            let mut buf = [0; 8192];
+            let n = try_ready!(self.reader.poll_read(&mut buf));
+            if n == 0 {
+                self.done = true;
+            } else {
+                self.input.extend_from_slice(&buf[..n]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_frames() {
+        assert_eq!(frame_header(&[0x81, 5, b'h']), Some((2, 5)));
+        assert_eq!(frame_header(&[0x81, 0x85, 1, 2, 3, 4]), Some((6, 5)));
+    }
+
+    #[test]
+    fn extended_lengths() {
+        assert_eq!(frame_header(&[0x82, 126, 0x01, 0x00]), Some((4, 256)));
+        assert_eq!(
+            frame_header(&[0x82, 127 | 0x80, 0, 0, 0, 1, 0, 0, 0, 0, 9, 9, 9, 9]),
+            Some((14, 1 << 32))
+        );
+    }
+
+    #[test]
+    fn incomplete_headers() {
+        assert_eq!(frame_header(&[0x81]), None);
+        assert_eq!(frame_header(&[0x81, 0x85, 1, 2]), None);
+        assert_eq!(frame_header(&[0x82, 126, 0x01]), None);
+        assert_eq!(frame_header(&[0x82, 127, 0, 0, 0]), None);
+    }
+}